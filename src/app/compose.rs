@@ -0,0 +1,240 @@
+use super::input::Input;
+use crate::protocol::human_size;
+
+/// The line that ends a `/paste`
+pub const PASTE_END: &str = "/end";
+/// The line that drops a `/paste` without sending it
+pub const PASTE_CANCEL: &str = "/cancel";
+
+/// A message longer than either of these is only sent once confirmed, in case a
+/// paste went to the wrong window
+const CONFIRM_LINES: usize = 10;
+const CONFIRM_BYTES: usize = 4096;
+
+/// Joins lines that end in a backslash onto the next one, so a message can be
+/// written over several lines from anywhere, a pipe included
+#[derive(Debug, Default)]
+pub struct Continuation {
+    message: String,
+}
+
+impl Continuation {
+    /// the finished message, or `None` while it's still being continued
+    pub fn feed(&mut self, line: String) -> Option<String> {
+        match line.strip_suffix('\\') {
+            Some(head) => {
+                self.message.push_str(head);
+                self.message.push('\n');
+                None
+            }
+            None => {
+                self.message.push_str(&line);
+                Some(std::mem::take(&mut self.message))
+            }
+        }
+    }
+}
+
+/// The lines of a message being pasted after `/paste`, taken as they are: nothing
+/// in them is a command, bar the lines that end the paste
+#[derive(Debug, Default)]
+pub struct Paste {
+    lines: Vec<String>,
+}
+
+/// What a line does to a `/paste` in progress
+#[derive(Debug, PartialEq)]
+pub enum PasteLine {
+    Collected,
+    /// the paste ended; this is the message, which is empty if nothing was pasted
+    Done(String),
+    Cancelled,
+}
+
+impl Paste {
+    pub fn feed(&mut self, line: String) -> PasteLine {
+        match line.as_str() {
+            PASTE_END => PasteLine::Done(std::mem::take(&mut self.lines).join("\n")),
+            PASTE_CANCEL => PasteLine::Cancelled,
+            _ => {
+                self.lines.push(line);
+                PasteLine::Collected
+            }
+        }
+    }
+}
+
+/// A message as it's being written: continued over lines that end in a backslash,
+/// pasted after `/paste`, or waiting for a yes because it's long. Every finished
+/// line goes through here before anything else looks at it.
+#[derive(Debug, Default)]
+pub struct Composer {
+    continuation: Continuation,
+    paste: Option<Paste>,
+    unconfirmed: Option<String>,
+}
+
+/// What a line of input came to
+#[derive(Debug, PartialEq)]
+pub enum Composed {
+    /// nothing to do until more lines come
+    Pending,
+    /// leave, dropping whatever was being written
+    Quit,
+    /// a line to act on as typed: a command, or a message that hasn't been through
+    /// `Composer::confirm` yet
+    Line(String),
+    /// a message ready to go
+    Send(String),
+    /// something to tell the user, and nothing to send
+    Note(String),
+}
+
+impl Composer {
+    pub fn feed(&mut self, input: Input) -> Composed {
+        let line = match input {
+            // before anything else, so nothing half-written can swallow it
+            Input::Quit => return Composed::Quit,
+            Input::Draft(_) => return Composed::Pending,
+            Input::Line(line) => line,
+        };
+
+        // everything after /paste is the message, commands and blank lines included
+        if let Some(paste) = &mut self.paste {
+            return match paste.feed(line) {
+                PasteLine::Collected => Composed::Pending,
+                PasteLine::Cancelled => {
+                    self.paste = None;
+                    Composed::Note("paste dropped".to_string())
+                }
+                PasteLine::Done(body) => {
+                    self.paste = None;
+                    if body.trim().is_empty() {
+                        Composed::Note("nothing was pasted".to_string())
+                    } else {
+                        self.confirm(body)
+                    }
+                }
+            };
+        }
+
+        if let Some(body) = self.unconfirmed.take() {
+            return match confirmed(&line) {
+                true => Composed::Send(body),
+                false => Composed::Note("not sent".to_string()),
+            };
+        }
+
+        match self.continuation.feed(line) {
+            Some(message) if !message.is_empty() => Composed::Line(message),
+            _ => Composed::Pending,
+        }
+    }
+
+    /// starts taking lines as they are, until `PASTE_END` or `PASTE_CANCEL`
+    pub fn paste(&mut self) {
+        self.paste = Some(Paste::default());
+    }
+
+    /// `body` to send, or the question to ask first if it's long
+    pub fn confirm(&mut self, body: String) -> Composed {
+        match confirmation(&body) {
+            Some(question) => {
+                self.unconfirmed = Some(body);
+                Composed::Note(question)
+            }
+            None => Composed::Send(body),
+        }
+    }
+}
+
+/// The question to ask before sending `body`, or `None` if it's short enough to
+/// send straight away
+pub fn confirmation(body: &str) -> Option<String> {
+    let lines = body.lines().count();
+
+    if lines <= CONFIRM_LINES && body.len() <= CONFIRM_BYTES {
+        return None;
+    }
+
+    Some(format!(
+        "that's {} lines ({}); send it? [y/N]",
+        lines,
+        human_size(body.len() as u64)
+    ))
+}
+
+/// whether the answer to a `confirmation` question was yes
+pub fn confirmed(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trailing_backslashes_continue_the_message() {
+        let mut continuation = Continuation::default();
+
+        assert_eq!(continuation.feed("hello\\".to_string()), None);
+        assert_eq!(continuation.feed("\\".to_string()), None);
+        assert_eq!(
+            continuation.feed("world".to_string()),
+            Some("hello\n\nworld".to_string())
+        );
+        assert_eq!(
+            continuation.feed("on its own".to_string()),
+            Some("on its own".to_string())
+        );
+    }
+
+    #[test]
+    fn paste_keeps_lines_verbatim_until_the_end_line() {
+        let mut paste = Paste::default();
+
+        for line in ["Traceback:", "", "/join is just text here", "  at main()"] {
+            assert_eq!(paste.feed(line.to_string()), PasteLine::Collected);
+        }
+        assert_eq!(
+            paste.feed(PASTE_END.to_string()),
+            PasteLine::Done("Traceback:\n\n/join is just text here\n  at main()".to_string())
+        );
+
+        assert_eq!(paste.feed("oops".to_string()), PasteLine::Collected);
+        assert_eq!(paste.feed(PASTE_CANCEL.to_string()), PasteLine::Cancelled);
+    }
+
+    #[test]
+    fn only_long_messages_need_confirming() {
+        assert_eq!(confirmation("one\ntwo\nthree"), None);
+        assert_eq!(
+            confirmation(&["line"; 12].join("\n")).unwrap(),
+            "that's 12 lines (59 B); send it? [y/N]"
+        );
+        assert!(confirmation(&"x".repeat(5000)).is_some());
+
+        assert!(confirmed(" Y "));
+        assert!(confirmed("yes"));
+        assert!(!confirmed(""));
+        assert!(!confirmed("nah"));
+    }
+
+    #[test]
+    fn quitting_leaves_whatever_is_half_written() {
+        let line = |s: &str| Input::Line(s.to_string());
+        let mut composer = Composer::default();
+
+        assert_eq!(composer.feed(line("first\\")), Composed::Pending);
+        assert_eq!(composer.feed(Input::Quit), Composed::Quit);
+
+        composer.paste();
+        assert_eq!(composer.feed(line("/quit")), Composed::Pending);
+        assert_eq!(composer.feed(Input::Quit), Composed::Quit);
+
+        let mut composer = Composer::default();
+        let long = ["line"; 12].join("\n");
+        assert!(matches!(composer.confirm(long), Composed::Note(_)));
+        assert_eq!(composer.feed(Input::Quit), Composed::Quit);
+    }
+}
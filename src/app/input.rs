@@ -0,0 +1,405 @@
+use std::io::{self, IsTerminal, Read, Write};
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+
+/// What the stdin reader hands to the session
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    /// a finished line, without its line ending
+    Line(String),
+    /// the line being typed changed; only a terminal reports these
+    Draft(String),
+    /// ctrl-c, or ctrl-d on an empty line
+    Quit,
+}
+
+/// Reads stdin until it closes or the session stops listening. A terminal is read a
+/// key at a time, so the session sees drafts as they're typed; anything else, like a
+/// pipe, a line at a time. Keep the returned guard until the session ends: dropping
+/// it puts the terminal back the way it was.
+pub fn spawn_reader(tx: mpsc::Sender<Input>) -> Option<RawMode> {
+    let raw = RawMode::enable();
+
+    if raw.is_some() {
+        std::thread::spawn(move || read_keys(tx));
+    } else {
+        tokio::spawn(read_lines(tx));
+    }
+
+    raw
+}
+
+async fn read_lines(tx: mpsc::Sender<Input>) {
+    let mut stdin = BufReader::new(tokio::io::stdin());
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+
+        let bytes = match stdin.read_line(&mut line).await {
+            Ok(n) => n,
+            Err(e) => {
+                eprintln!("stdin read error: {}", e);
+                break;
+            }
+        };
+
+        if bytes == 0 {
+            break;
+        }
+
+        // blank lines go through too: they matter to a /paste
+        let msg = line.trim_end_matches(&['\n', '\r'][..]);
+
+        if tx.send(Input::Line(msg.to_string())).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Runs on its own thread, since reading a terminal byte by byte blocks
+fn read_keys(tx: mpsc::Sender<Input>) {
+    let mut stdin = io::stdin().lock();
+    let mut editor = LineEditor::default();
+    let mut buf = [0u8; 64];
+
+    loop {
+        let n = match stdin.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                eprintln!("stdin read error: {}", e);
+                break;
+            }
+        };
+
+        for &byte in &buf[..n] {
+            let input = match editor.feed(byte) {
+                Keypress::Edited { echo } => {
+                    print!("{}", echo);
+                    let _ = io::stdout().flush();
+                    Input::Draft(editor.line.clone())
+                }
+                Keypress::Submitted(line) => {
+                    println!();
+                    Input::Line(line)
+                }
+                // ctrl-c and ctrl-d leave the way /quit does, so the terminal gets restored
+                Keypress::Quit => {
+                    println!();
+                    Input::Quit
+                }
+                Keypress::Ignored => continue,
+            };
+
+            if tx.blocking_send(input).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// What one byte of terminal input amounts to
+#[derive(Debug, PartialEq)]
+enum Keypress {
+    /// the line changed; `echo` shows the change on screen
+    Edited {
+        echo: String,
+    },
+    Submitted(String),
+    /// ctrl-c, or ctrl-d on an empty line
+    Quit,
+    /// part of a character or escape sequence, or a key we don't handle
+    Ignored,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Escape {
+    Start,
+    /// `ESC [`, ended by a byte in `@`..=`~`
+    Csi,
+    /// `ESC O`, ended by the next byte
+    Ss3,
+}
+
+/// The line being typed, with just enough editing for a chat line: backspace and
+/// ctrl-u. Shift+Enter (where the terminal reports it) and Alt+Enter start a new
+/// line in the same message, as do line endings inside a bracketed paste. Arrow keys
+/// and the like are swallowed rather than echoed as garbage.
+#[derive(Debug, Default)]
+struct LineEditor {
+    line: String,
+    // the bytes so far of a character that takes more than one
+    partial: Vec<u8>,
+    escape: Option<Escape>,
+    // the parameters of the CSI sequence being read, e.g. "13;2"
+    csi: String,
+    // between the terminal's paste start and end markers
+    pasting: bool,
+    previous: u8,
+}
+
+impl LineEditor {
+    fn feed(&mut self, byte: u8) -> Keypress {
+        let key = self.key(byte);
+        self.previous = byte;
+        key
+    }
+
+    fn key(&mut self, byte: u8) -> Keypress {
+        if let Some(escape) = self.escape.take() {
+            match (escape, byte) {
+                (Escape::Start, b'[') => {
+                    self.csi.clear();
+                    self.escape = Some(Escape::Csi);
+                }
+                (Escape::Start, b'O') => self.escape = Some(Escape::Ss3),
+                // alt+enter
+                (Escape::Start, b'\r') => return self.new_line(),
+                // parameters and intermediates; anything past them ends the sequence
+                (Escape::Csi, 0x20..=0x3f) => {
+                    self.csi.push(byte as char);
+                    self.escape = Some(Escape::Csi);
+                }
+                (Escape::Csi, end) => return self.csi_key(end),
+                _ => {}
+            }
+            return Keypress::Ignored;
+        }
+
+        match byte {
+            // a pasted \r\n is one line ending, not two
+            b'\n' if self.pasting && self.previous == b'\r' => Keypress::Ignored,
+            b'\r' | b'\n' if self.pasting => self.new_line(),
+            b'\t' if self.pasting => {
+                self.line.push('\t');
+                Keypress::Edited {
+                    echo: "\t".to_string(),
+                }
+            }
+            b'\r' | b'\n' => {
+                self.partial.clear();
+                Keypress::Submitted(std::mem::take(&mut self.line))
+            }
+            0x7f | 0x08 => match self.line.pop() {
+                // back to the end of the line above
+                Some('\n') => {
+                    let column = self.line.rsplit('\n').next().unwrap_or("").chars().count();
+                    let mut echo = "\x1b[A\r".to_string();
+                    if column > 0 {
+                        echo.push_str(&format!("\x1b[{}C", column));
+                    }
+                    Keypress::Edited { echo }
+                }
+                Some(_) => Keypress::Edited {
+                    echo: "\x08 \x08".to_string(),
+                },
+                None => Keypress::Ignored,
+            },
+            0x1b => {
+                self.escape = Some(Escape::Start);
+                Keypress::Ignored
+            }
+            // pasted text can't quit or clear the line
+            0x00..=0x1f if self.pasting => Keypress::Ignored,
+            // ctrl-u
+            0x15 if !self.line.is_empty() => {
+                let echo = erase(&self.line);
+                self.line.clear();
+                Keypress::Edited { echo }
+            }
+            0x03 => Keypress::Quit,
+            0x04 if self.line.is_empty() => Keypress::Quit,
+            0x00..=0x1f => Keypress::Ignored,
+            _ => {
+                self.partial.push(byte);
+                match std::str::from_utf8(&self.partial) {
+                    Ok(text) => {
+                        let echo = text.to_string();
+                        self.partial.clear();
+                        self.line.push_str(&echo);
+                        Keypress::Edited { echo }
+                    }
+                    // the rest of the character is still to come
+                    Err(e) if e.error_len().is_none() => Keypress::Ignored,
+                    Err(_) => {
+                        self.partial.clear();
+                        Keypress::Ignored
+                    }
+                }
+            }
+        }
+    }
+
+    fn new_line(&mut self) -> Keypress {
+        self.line.push('\n');
+        Keypress::Edited {
+            echo: "\n".to_string(),
+        }
+    }
+
+    fn csi_key(&mut self, end: u8) -> Keypress {
+        match (self.csi.as_str(), end) {
+            // shift+enter, from terminals that tell it apart from enter
+            ("13;2", b'u') | ("27;2;13", b'~') => self.new_line(),
+            ("200", b'~') => {
+                self.pasting = true;
+                Keypress::Ignored
+            }
+            ("201", b'~') => {
+                self.pasting = false;
+                Keypress::Ignored
+            }
+            _ => Keypress::Ignored,
+        }
+    }
+}
+
+/// What wipes `draft` off the screen, however many lines it takes up, leaving the
+/// cursor where it started
+pub fn erase(draft: &str) -> String {
+    let mut echo = "\r\x1b[2K".to_string();
+    for _ in draft.matches('\n') {
+        echo.push_str("\x1b[A\x1b[2K");
+    }
+    echo
+}
+
+/// The terminal settings from before we switched to reading keys, restored on drop
+pub struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    /// turns off line buffering and echo on stdin, or returns `None` if stdin isn't
+    /// a terminal
+    fn enable() -> Option<Self> {
+        if !io::stdin().is_terminal() {
+            return None;
+        }
+
+        // SAFETY: termios is plain data, and both calls only read or write through the
+        // pointer we give them
+        unsafe {
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return None;
+            }
+
+            let mut raw = original;
+            // with ISIG off ctrl-c arrives as a key, so we exit cleanly instead of
+            // leaving the terminal without echo
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return None;
+            }
+
+            // bracketed paste: the terminal marks where pasted text starts and ends,
+            // so a pasted line ending doesn't send the message
+            print!("\x1b[?2004h");
+            let _ = io::stdout().flush();
+
+            Some(Self { original })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        print!("\x1b[?2004l");
+        let _ = io::stdout().flush();
+
+        // SAFETY: as in `enable`
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(editor: &mut LineEditor, bytes: &[u8]) -> Vec<Keypress> {
+        bytes.iter().map(|&byte| editor.feed(byte)).collect()
+    }
+
+    #[test]
+    fn typing_and_backspace_edit_the_line_until_enter() {
+        let mut editor = LineEditor::default();
+
+        feed(&mut editor, b"hellp\x7fo");
+        assert_eq!(editor.line, "hello");
+
+        let keys = feed(&mut editor, "é\n".as_bytes());
+        // the first byte of é is held back until the second arrives
+        assert_eq!(keys[0], Keypress::Ignored);
+        assert_eq!(
+            keys[1],
+            Keypress::Edited {
+                echo: "é".to_string()
+            }
+        );
+        assert_eq!(keys[2], Keypress::Submitted("helloé".to_string()));
+        assert!(editor.line.is_empty());
+    }
+
+    #[test]
+    fn escape_sequences_are_swallowed_whole() {
+        let mut editor = LineEditor::default();
+
+        // up arrow, ctrl-left, then an F1 sent as SS3
+        let keys = feed(&mut editor, b"a\x1b[A\x1b[1;5D\x1bOPb");
+        assert_eq!(editor.line, "ab");
+        assert_eq!(
+            keys.iter()
+                .filter(|key| matches!(key, Keypress::Edited { .. }))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn ctrl_u_clears_and_ctrl_d_only_quits_on_an_empty_line() {
+        let mut editor = LineEditor::default();
+
+        feed(&mut editor, b"oops");
+        assert_eq!(editor.feed(0x04), Keypress::Ignored);
+        assert!(matches!(editor.feed(0x15), Keypress::Edited { .. }));
+        assert!(editor.line.is_empty());
+        assert_eq!(editor.feed(0x04), Keypress::Quit);
+        assert_eq!(editor.feed(0x03), Keypress::Quit);
+    }
+
+    #[test]
+    fn shift_enter_and_pasted_line_endings_continue_the_message() {
+        let mut editor = LineEditor::default();
+
+        // shift+enter both ways terminals report it, then alt+enter
+        feed(&mut editor, b"one\x1b[13;2utwo\x1b[27;2;13~three\x1b\rfour");
+        assert_eq!(editor.line, "one\ntwo\nthree\nfour");
+
+        // backspacing over a line break goes back to the end of the line above
+        editor.line = "ab\n".to_string();
+        assert_eq!(
+            editor.feed(0x7f),
+            Keypress::Edited {
+                echo: "\x1b[A\r\x1b[2C".to_string()
+            }
+        );
+        editor.line.clear();
+
+        let keys = feed(
+            &mut editor,
+            b"\x1b[200~at main()\r\n\tat run()\x03\x1b[201~\r",
+        );
+        assert_eq!(
+            keys.last(),
+            Some(&Keypress::Submitted("at main()\n\tat run()".to_string()))
+        );
+        assert_eq!(erase("a\nb\nc"), "\r\x1b[2K\x1b[A\x1b[2K\x1b[A\x1b[2K");
+    }
+}
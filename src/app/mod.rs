@@ -0,0 +1,1869 @@
+mod compose;
+mod input;
+mod style;
+mod transcript;
+mod typing;
+
+use crate::backend::matrix::{ConnectOptions, Credentials, MatrixBackend};
+use crate::backend::p2p::P2PBackend;
+use crate::backend::ChatBackend;
+use crate::cli::{Alert, Cli, Command, MatrixConnection};
+use crate::protocol::{
+    Attention, ChatEvent, ChatMessage, DirectoryRoom, HistoryVisibility, JoinRule,
+    MembershipChange, MessageId, MessageKind, MessageOrigin, NewRoom, NotifyLevel, Presence,
+    PresenceState, RichText, RoomId, RoomSetting, SearchHit, SendStatus,
+};
+
+use std::collections::{HashMap, HashSet};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::Context;
+use chrono::{DateTime, Local, Utc};
+use matrix_sdk::ruma::{OwnedUserId, ServerName};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
+use uuid::Uuid;
+
+use compose::{Composed, Composer, PASTE_CANCEL, PASTE_END};
+use input::Input;
+use transcript::{Entry, Transcript, SCROLLBACK_LEN};
+use typing::{typing_status, TypingNotifier};
+
+/// How the interactive session behaves, whichever backend it's on
+#[derive(Debug, Clone, Copy)]
+struct SessionOptions {
+    alert: Alert,
+    /// how long without input before we're shown as away, if ever
+    away_after: Option<Duration>,
+    /// answer direct messages while away
+    auto_reply: bool,
+}
+
+pub async fn run(cli: Cli) -> anyhow::Result<()> {
+    let session = SessionOptions {
+        alert: cli.alert,
+        away_after: (cli.away_after > 0).then(|| Duration::from_secs(cli.away_after * 60)),
+        auto_reply: cli.auto_reply,
+    };
+
+    match cli.command {
+        Command::Server { port, username } => {
+            println!("Starting server on port: {} as '{}'", port, username);
+
+            let backend = P2PBackend::listen(port, username).await?;
+
+            run_interactive(Box::new(backend), session).await
+        }
+        Command::Client {
+            host,
+            port,
+            username,
+        } => {
+            println!(
+                "Connecting to host: {} on port: {} as '{}'",
+                host, port, username
+            );
+
+            let backend = P2PBackend::connect(&host, port, username).await?;
+
+            run_interactive(Box::new(backend), session).await
+        }
+        Command::Matrix {
+            homeserver,
+            user_id,
+            password,
+            access_token,
+            sso,
+            guest,
+            connection,
+            auto_accept_from,
+        } => {
+            let credentials = match (user_id, password, access_token) {
+                _ if guest => Credentials::Guest,
+                _ if sso => Credentials::Sso,
+                (_, _, Some(token)) => Credentials::AccessToken(token),
+                (Some(user_id), Some(password), None) => {
+                    Credentials::Password { user_id, password }
+                }
+                // clap insists on one of the above
+                _ => anyhow::bail!("no way to log in given"),
+            };
+
+            run_matrix(
+                &homeserver,
+                credentials,
+                connection,
+                auto_accept_from,
+                session,
+            )
+            .await
+        }
+        Command::Register {
+            homeserver,
+            username,
+            password,
+            connection,
+        } => {
+            let credentials = Credentials::Register { username, password };
+
+            run_matrix(&homeserver, credentials, connection, Vec::new(), session).await
+        }
+    }
+}
+
+async fn run_matrix(
+    homeserver: &str,
+    credentials: Credentials,
+    connection: MatrixConnection,
+    auto_accept_from: Vec<String>,
+    session: SessionOptions,
+) -> anyhow::Result<()> {
+    let login = match &credentials {
+        Credentials::Password { user_id, .. } => format!("as '{}'", user_id),
+        Credentials::AccessToken(_) => "with an access token".to_string(),
+        Credentials::Sso => "with single sign-on".to_string(),
+        Credentials::Register { username, .. } => format!("as new account '{}'", username),
+        Credentials::Guest => "as a guest".to_string(),
+    };
+    println!(
+        "Connecting to matrix homeserver: {} {}{}",
+        homeserver,
+        login,
+        if connection.insecure {
+            " (insecure, no TLS)"
+        } else {
+            ""
+        }
+    );
+
+    let server_name = <&ServerName>::try_from(homeserver)
+        .with_context(|| format!("'{}' is not a valid homeserver name", homeserver))?;
+
+    let auto_accept_from = auto_accept_from
+        .iter()
+        .map(|user| {
+            OwnedUserId::try_from(user.as_str())
+                .with_context(|| format!("'{}' is not a valid Matrix user id", user))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let options = ConnectOptions {
+        insecure: connection.insecure,
+        homeserver_url: connection.homeserver_url,
+        ca_cert: connection.ca_cert,
+        client_cert: connection.client_cert,
+        client_key: connection.client_key,
+        proxy: connection.proxy,
+    };
+
+    let backend =
+        MatrixBackend::login(server_name, &credentials, &options, auto_accept_from).await?;
+    if let Credentials::Guest = credentials {
+        println!("Watching as a read-only guest: /join public rooms that allow guests");
+    }
+
+    run_interactive(Box::new(backend), session).await
+}
+
+async fn run_interactive(
+    mut backend: Box<dyn ChatBackend>,
+    session: SessionOptions,
+) -> anyhow::Result<()> {
+    let (input_tx, mut input_rx) = mpsc::channel::<Input>(64);
+
+    // restores the terminal when the session ends, however it ends
+    let raw_mode = input::spawn_reader(input_tx);
+    // only a terminal being typed into has a status line to keep above the draft
+    let has_status_line = raw_mode.is_some() && std::io::stdout().is_terminal();
+
+    let mut current_room = RoomId::default();
+    // results of the last /directory search, so `/join <n>` can pick one
+    let mut directory: Vec<DirectoryRoom> = Vec::new();
+    // what we've received and sent; backends never echo our own messages back
+    let mut transcript = Transcript::new(SCROLLBACK_LEN);
+    // root of the thread entered with /thread; what we send goes there instead of the room
+    let mut current_thread: Option<MessageId> = None;
+    // results of the last /search, so `/goto <n>` can pick one
+    let mut search_results: Vec<SearchHit> = Vec::new();
+    // the line being typed, so incoming messages can be printed above it
+    let mut draft = String::new();
+    let mut typing_notifier = TypingNotifier::default();
+    // who the last typing notice for each room said is typing
+    let mut typing: HashMap<RoomId, Vec<String>> = HashMap::new();
+    // the status line on screen above the draft, if there's one showing
+    let mut status: Option<String> = None;
+    // what we last told the backend about ourselves; `auto_away` if idling set it
+    let mut presence = Presence::default();
+    let mut auto_away = false;
+    // set when input ended an auto-away, until the next line entered
+    let mut welcomed_back = false;
+    let mut last_input = Instant::now();
+    // everyone else's, by the name on their messages
+    let mut presence_of: HashMap<String, Presence> = HashMap::new();
+    // who has had an away reply since we went away; each gets just the one
+    let mut auto_replied: HashSet<String> = HashSet::new();
+    // continued lines, a /paste, or a long message waiting for a yes
+    let mut composer = Composer::default();
+
+    loop {
+        // this while loop empties the 'input_rx' channel
+        while let Ok(input) = input_rx.try_recv() {
+            last_input = Instant::now();
+            if auto_away {
+                auto_away = false;
+                presence = Presence::default();
+                if backend.set_presence(&presence).await.is_ok() {
+                    println!("[system]: welcome back, no longer away");
+                    welcomed_back = true;
+                }
+            }
+
+            if let Input::Draft(text) = &input {
+                draft = text.clone();
+                for (room, now_typing) in
+                    typing_notifier.draft_changed(&current_room, &draft, Instant::now())
+                {
+                    // a lost typing notice isn't worth interrupting the user for
+                    let _ = backend.send_typing(&room, now_typing).await;
+                }
+                continue;
+            }
+
+            // the status line is now above the line just entered; it's put back below
+            if let Some(shown) = status.take() {
+                println!(
+                    "{}{}",
+                    input::erase(&format!("{}\n{}\n", shown, draft)),
+                    draft
+                );
+            }
+            draft.clear();
+            for (room, now_typing) in
+                typing_notifier.draft_changed(&current_room, "", Instant::now())
+            {
+                let _ = backend.send_typing(&room, now_typing).await;
+            }
+
+            let msg = match composer.feed(input) {
+                Composed::Pending => continue,
+                Composed::Quit => {
+                    println!("exiting interactive loop, goodbye...");
+                    return Ok(());
+                }
+                Composed::Note(note) => {
+                    println!("[system]: {}", note);
+                    continue;
+                }
+                Composed::Send(body) => {
+                    let thread = current_thread.as_ref();
+                    send_and_echo(
+                        backend.as_mut(),
+                        &mut transcript,
+                        &current_room,
+                        thread,
+                        &body,
+                    )
+                    .await;
+                    continue;
+                }
+                Composed::Line(msg) => msg,
+            };
+            // only the line typed to come back counts as having just come back
+            let just_welcomed_back = std::mem::take(&mut welcomed_back);
+
+            if msg == "/quit" {
+                println!("exiting interactive loop, goodbye...");
+                return Ok(());
+            }
+
+            if msg == "/paste" {
+                composer.paste();
+                println!(
+                    "[system]: pasting; send it with a line that's just {}, or drop it with {}",
+                    PASTE_END, PASTE_CANCEL
+                );
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/join ") {
+                let trimmed = rest.trim();
+
+                if trimmed.is_empty() {
+                    println!("[system]: usage: /join <room>");
+                    continue;
+                }
+
+                let picked = trimmed
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| n.checked_sub(1))
+                    .and_then(|i| directory.get(i));
+
+                let typed = match picked {
+                    Some(found) => found.address(),
+                    None => RoomId::new(trimmed),
+                };
+
+                // compare and join by the backend's own id for the room, so an alias
+                // and the id it points at count as the same room
+                let room = match backend.resolve_room(&typed).await {
+                    Ok(room) => room,
+                    Err(e) => {
+                        println!("[system]: {:#}", e);
+                        continue;
+                    }
+                };
+
+                if room == current_room {
+                    println!(
+                        "[system]: already in {}",
+                        room_label(backend.as_ref(), &current_room)
+                    );
+                    continue;
+                }
+
+                if current_room != RoomId::default() {
+                    backend.leave_room(&current_room).await?;
+                }
+
+                current_room = room;
+                current_thread = None;
+                backend.join_room(&current_room).await?;
+                println!(
+                    "[system]: joined {}",
+                    room_label(backend.as_ref(), &current_room)
+                );
+                mark_read(backend.as_mut(), &transcript, &current_room).await;
+                continue;
+            }
+
+            if msg == "/leave" {
+                if current_room == RoomId::default() {
+                    println!("[system]: already in default room");
+                    continue;
+                }
+                backend.leave_room(&current_room).await?;
+                println!(
+                    "[system]: left {}, back to default",
+                    room_label(backend.as_ref(), &current_room)
+                );
+                current_room = RoomId::default();
+                current_thread = None;
+                backend.join_room(&current_room).await?;
+                mark_read(backend.as_mut(), &transcript, &current_room).await;
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/dm ") {
+                let user = rest.trim();
+                if user.is_empty() {
+                    println!("[system]: usage: /dm <user>");
+                    continue;
+                }
+
+                // unlike /join this doesn't leave the room we were in: a DM is somewhere
+                // to switch to, not instead of
+                match backend.open_dm(user).await {
+                    Ok(room) => {
+                        current_room = room;
+                        current_thread = None;
+                        println!(
+                            "[system]: now messaging {}",
+                            room_label(backend.as_ref(), &current_room)
+                        );
+                        mark_read(backend.as_mut(), &transcript, &current_room).await;
+                    }
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/notify ") {
+                let level = match rest.trim() {
+                    "all" => NotifyLevel::All,
+                    "mentions" => NotifyLevel::Mentions,
+                    "muted" | "mute" => NotifyLevel::Muted,
+                    _ => {
+                        println!("[system]: usage: /notify <all|mentions|muted>");
+                        continue;
+                    }
+                };
+
+                match backend.set_notify_level(&current_room, level).await {
+                    Ok(()) => println!(
+                        "[system]: notifications for {}: {}",
+                        room_label(backend.as_ref(), &current_room),
+                        level
+                    ),
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if msg == "/away" || msg.starts_with("/away ") {
+                let message = msg.trim_start_matches("/away").trim();
+                let away = Presence::away((!message.is_empty()).then(|| message.to_string()));
+
+                match backend.set_presence(&away).await {
+                    Ok(()) => {
+                        println!("[system]: you're {}", away);
+                        presence = away;
+                        auto_replied.clear();
+                    }
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if msg == "/back" {
+                if presence.state == PresenceState::Online {
+                    // typing /back after idling already brought us back, and said so
+                    if !just_welcomed_back {
+                        println!("[system]: you're not away");
+                    }
+                    continue;
+                }
+
+                match backend.set_presence(&Presence::default()).await {
+                    Ok(()) => {
+                        println!("[system]: you're back");
+                        presence = Presence::default();
+                    }
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if msg == "/members" {
+                match backend.list_members(&current_room).await {
+                    Ok(members) => {
+                        println!(
+                            "[system]: {} members of {}:",
+                            members.len(),
+                            room_label(backend.as_ref(), &current_room)
+                        );
+                        let own_user = backend.own_user();
+                        for member in members {
+                            let known = if member == own_user {
+                                Some(&presence)
+                            } else {
+                                presence_of.get(&member)
+                            };
+                            match known {
+                                Some(known) => println!("  {} ({})", member, known),
+                                None => println!("  {}", member),
+                            }
+                        }
+                    }
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if msg == "/rooms" {
+                match backend.list_rooms().await {
+                    Ok(rooms) if rooms.is_empty() => println!("[system]: not in any rooms"),
+                    Ok(rooms) => {
+                        if rooms.iter().any(|room| room.direct) {
+                            println!("[system]: joined rooms (* = new direct messages):");
+                        } else {
+                            println!("[system]: joined rooms:");
+                        }
+                        for room in rooms {
+                            // unread direct messages are meant for us personally, so they stand out
+                            let marker = if room.direct && room.unread > 0 {
+                                '*'
+                            } else {
+                                ' '
+                            };
+                            println!("{} {}", marker, room);
+                        }
+                    }
+                    Err(e) => println!("[system]: couldn't list rooms: {:#}", e),
+                }
+                continue;
+            }
+
+            if msg == "/directory" || msg.starts_with("/directory ") {
+                let args = msg.trim_start_matches("/directory");
+                let (query, server) = match parse_directory_args(args) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        println!("[system]: {}", e);
+                        println!("[system]: usage: /directory [query] [--server <server>]");
+                        continue;
+                    }
+                };
+
+                match backend
+                    .search_directory(query.as_deref(), server.as_deref())
+                    .await
+                {
+                    Ok(rooms) if rooms.is_empty() => println!("[system]: no public rooms found"),
+                    Ok(rooms) => {
+                        println!("[system]: public rooms (/join <number> to join one):");
+                        for (i, room) in rooms.iter().enumerate() {
+                            println!("  {}. {}", i + 1, room);
+                        }
+                        directory = rooms;
+                    }
+                    Err(e) => println!("[system]: directory search failed: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/create ") {
+                let new_room = match parse_new_room(rest) {
+                    Ok(new_room) => new_room,
+                    Err(e) => {
+                        println!("[system]: {}", e);
+                        println!(
+                            "[system]: usage: /create <name> [--public|--private] [--encrypted]"
+                        );
+                        continue;
+                    }
+                };
+
+                let room = match backend.create_room(&new_room).await {
+                    Ok(room) => room,
+                    Err(e) => {
+                        println!("[system]: create failed: {:#}", e);
+                        continue;
+                    }
+                };
+
+                if current_room != RoomId::default() {
+                    backend.leave_room(&current_room).await?;
+                }
+
+                current_room = room;
+                current_thread = None;
+                println!(
+                    "[system]: created and joined {}",
+                    room_label(backend.as_ref(), &current_room)
+                );
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/invite ") {
+                let user = rest.trim();
+
+                if user.is_empty() {
+                    println!("[system]: usage: /invite <user>");
+                    continue;
+                }
+
+                match backend.invite_user(&current_room, user).await {
+                    Ok(()) => println!("[system]: invited {} to {}", user, current_room),
+                    Err(e) => println!("[system]: invite failed: {:#}", e),
+                }
+                continue;
+            }
+
+            if msg == "/invites" {
+                match backend.pending_invites().await {
+                    Ok(invites) if invites.is_empty() => println!("[system]: no pending invites"),
+                    Ok(invites) => {
+                        println!("[system]: pending invites:");
+                        for invite in invites {
+                            println!("  {}", invite);
+                        }
+                    }
+                    Err(e) => println!("[system]: couldn't list invites: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/accept ") {
+                let room = RoomId::new(rest.trim());
+
+                match backend.accept_invite(&room).await {
+                    Ok(()) => println!("[system]: accepted invite, /join {} to switch to it", room),
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/decline ") {
+                let room = RoomId::new(rest.trim());
+
+                match backend.decline_invite(&room).await {
+                    Ok(()) => println!("[system]: declined invite to {}", room),
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/nick ") {
+                let Some((name, room_only)) = parse_nick(rest) else {
+                    println!("[system]: usage: /nick [--room] <name>");
+                    continue;
+                };
+                let room = room_only.then_some(&current_room);
+
+                match backend.set_display_name(name, room).await {
+                    Ok(()) => match room {
+                        Some(room) => println!(
+                            "[system]: you are now {} in {}",
+                            name,
+                            room_label(backend.as_ref(), room)
+                        ),
+                        None => println!("[system]: you are now {}", name),
+                    },
+                    Err(e) => println!("[system]: couldn't change name: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/upload ") {
+                let path = rest.trim();
+
+                if path.is_empty() {
+                    println!("[system]: usage: /upload <path>");
+                    continue;
+                }
+
+                match backend
+                    .send_attachment(&current_room, Path::new(path))
+                    .await
+                {
+                    Ok(()) => println!("[system]: uploaded {} to {}", path, current_room),
+                    Err(e) => println!("[system]: upload failed: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/download ") {
+                let rest = rest.trim();
+                let (id, dest) = match rest.split_once(char::is_whitespace) {
+                    Some((id, dest)) => (id, Some(Path::new(dest.trim()))),
+                    None => (rest, None),
+                };
+
+                if id.is_empty() {
+                    println!("[system]: usage: /download <message-id> [dest]");
+                    continue;
+                }
+
+                let id = transcript.resolve(id);
+                match backend.download_attachment(&id, dest).await {
+                    Ok(path) => println!("[system]: saved attachment to {}", path.display()),
+                    Err(e) => println!("[system]: download failed: {:#}", e),
+                }
+                continue;
+            }
+
+            if msg == "/retry" || msg.starts_with("/retry ") {
+                let arg = msg.trim_start_matches("/retry").trim();
+                let id = match arg {
+                    "" => None,
+                    arg => match Uuid::parse_str(arg) {
+                        Ok(id) => Some(id),
+                        Err(_) => {
+                            println!("[system]: usage: /retry [message-id]");
+                            continue;
+                        }
+                    },
+                };
+
+                match backend.retry_failed(id.as_ref()).await {
+                    Ok(0) => println!("[system]: no failed messages to retry"),
+                    Ok(n) => println!("[system]: retrying {} message(s)", n),
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/reply ") {
+                let Some((id, body)) = split_id(rest) else {
+                    println!("[system]: usage: /reply <message-id> <text>");
+                    continue;
+                };
+
+                let to = transcript.resolve(id);
+                let sent = match &current_thread {
+                    Some(root) => {
+                        backend
+                            .send_to_thread(&current_room, root, Some(&to), body)
+                            .await
+                    }
+                    None => backend.send_reply(&current_room, &to, body).await,
+                };
+
+                match sent {
+                    Ok(local_id) => {
+                        let mut echo = local_echo(backend.as_ref(), local_id, &current_room, body);
+                        echo.in_reply_to = Some(to);
+                        echo.thread = current_thread.clone();
+                        println!(
+                            "{}",
+                            render_message(&echo, |room| room_label(backend.as_ref(), room))
+                        );
+                        transcript.push_local(local_id, echo);
+                    }
+                    Err(e) => println!("[system]: reply failed: {:#}", e),
+                }
+                continue;
+            }
+
+            // edits and deletions come back from the backend as events, which update the transcript
+            if let Some(rest) = msg.strip_prefix("/edit ") {
+                let Some((id, body)) = split_id(rest) else {
+                    println!("[system]: usage: /edit <message-id> <text>");
+                    continue;
+                };
+
+                let id = transcript.resolve(id);
+                if let Err(e) = backend.edit_message(&current_room, &id, body).await {
+                    println!("[system]: edit failed: {:#}", e);
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/delete ") {
+                let (id, reason) = split_reason(rest);
+
+                if id.is_empty() {
+                    println!("[system]: usage: /delete <message-id> [reason]");
+                    continue;
+                }
+
+                let id = transcript.resolve(id);
+                if let Err(e) = backend.delete_message(&current_room, &id, reason).await {
+                    println!("[system]: delete failed: {:#}", e);
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/react ") {
+                let Some((id, key)) = split_id(rest) else {
+                    println!("[system]: usage: /react <message-id> <emoji>");
+                    continue;
+                };
+
+                let id = transcript.resolve(id);
+                if let Err(e) = backend.react(&current_room, &id, key).await {
+                    println!("[system]: reaction failed: {:#}", e);
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/unreact ") {
+                let Some((id, key)) = split_id(rest) else {
+                    println!("[system]: usage: /unreact <message-id> <emoji>");
+                    continue;
+                };
+
+                let id = transcript.resolve(id);
+                if let Err(e) = backend.unreact(&current_room, &id, key).await {
+                    println!("[system]: {:#}", e);
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/room ") {
+                let setting = match parse_room_setting(rest) {
+                    Ok(setting) => setting,
+                    Err(e) => {
+                        println!("[system]: {}", e);
+                        println!("[system]: usage: /room name|topic <text>, /room avatar <path>, /room join-rule public|invite|knock, /room history world-readable|shared|invited|joined");
+                        continue;
+                    }
+                };
+
+                match backend.update_room(&current_room, &setting).await {
+                    Ok(()) => println!(
+                        "[system]: updated {}",
+                        room_label(backend.as_ref(), &current_room)
+                    ),
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/kick ") {
+                let (user, reason) = split_reason(rest);
+                if user.is_empty() {
+                    println!("[system]: usage: /kick <user> [reason]");
+                    continue;
+                }
+                match backend.kick_user(&current_room, user, reason).await {
+                    Ok(()) => println!("[system]: kicked {}", user),
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/ban ") {
+                let (user, reason) = split_reason(rest);
+                if user.is_empty() {
+                    println!("[system]: usage: /ban <user> [reason]");
+                    continue;
+                }
+                match backend.ban_user(&current_room, user, reason).await {
+                    Ok(()) => println!("[system]: banned {}", user),
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/unban ") {
+                let user = rest.trim();
+                if user.is_empty() {
+                    println!("[system]: usage: /unban <user>");
+                    continue;
+                }
+                match backend.unban_user(&current_room, user).await {
+                    Ok(()) => println!("[system]: unbanned {}", user),
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if msg == "/power" {
+                match backend.power_levels(&current_room).await {
+                    Ok(levels) => {
+                        println!(
+                            "[system]: power levels in {}:",
+                            room_label(backend.as_ref(), &current_room)
+                        );
+                        for line in levels.to_string().lines() {
+                            println!("  {}", line);
+                        }
+                    }
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/power ") {
+                let Some((user, level)) = split_id(rest) else {
+                    println!("[system]: usage: /power [<user> <level>]");
+                    continue;
+                };
+                let Ok(level) = level.parse::<i64>() else {
+                    println!("[system]: '{}' is not a number", level);
+                    continue;
+                };
+
+                match backend.set_power_level(&current_room, user, level).await {
+                    Ok(()) => println!("[system]: {} now has power level {}", user, level),
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if msg == "/thread" || msg.starts_with("/thread ") {
+                let arg = msg.trim_start_matches("/thread").trim();
+
+                if arg.is_empty() {
+                    match current_thread.take() {
+                        Some(root) => println!("[system]: left thread {}", root),
+                        None => println!("[system]: usage: /thread <root-message-id>"),
+                    }
+                    continue;
+                }
+
+                if !backend.supports_threads() {
+                    println!("[system]: this backend doesn't support threads");
+                    continue;
+                }
+
+                let root = transcript.resolve(arg);
+                println!(
+                    "[system]: in thread {} - messages go there until /thread on its own",
+                    root
+                );
+                for entry in transcript.thread(&root) {
+                    println!(
+                        "{}",
+                        render_entry(entry, |room| room_label(backend.as_ref(), room))
+                    );
+                }
+                current_thread = Some(root);
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/search ") {
+                let (terms, room) = match parse_search_args(rest) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        println!("[system]: {}", e);
+                        println!("[system]: usage: /search <terms> [--room <room>]");
+                        continue;
+                    }
+                };
+
+                let room = match room {
+                    Some(room) => match backend.resolve_room(&RoomId::new(room)).await {
+                        Ok(room) => Some(room),
+                        Err(e) => {
+                            println!("[system]: {:#}", e);
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+
+                match backend.search_messages(&terms, room.as_ref()).await {
+                    Ok(hits) if hits.is_empty() => println!("[system]: no messages found"),
+                    Ok(hits) => {
+                        println!(
+                            "[system]: search results (/goto <number> to see one in context):"
+                        );
+                        for (i, hit) in hits.iter().enumerate() {
+                            let message = &hit.message;
+                            let mut line = format!(
+                                "  {}. [{}] {} {}: {}",
+                                i + 1,
+                                room_label(backend.as_ref(), &message.room),
+                                format_ts(&message.ts),
+                                message.from,
+                                snippet(&message.body, &terms, SNIPPET_LEN)
+                            );
+                            if let Some(rank) = hit.rank {
+                                line.push_str(&format!(" (score {:.2})", rank));
+                            }
+                            println!("{}", line);
+                        }
+                        search_results = hits;
+                    }
+                    Err(e) => println!("[system]: search failed: {:#}", e),
+                }
+                continue;
+            }
+
+            if let Some(rest) = msg.strip_prefix("/goto ") {
+                let trimmed = rest.trim();
+
+                let picked = trimmed
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| n.checked_sub(1))
+                    .and_then(|i| search_results.get(i));
+
+                let (room, id) = match picked {
+                    Some(hit) => (hit.message.room.clone(), hit.message.id.clone()),
+                    None => (current_room.clone(), transcript.resolve(trimmed)),
+                };
+
+                match backend.message_context(&room, &id).await {
+                    Ok(messages) => {
+                        for message in &messages {
+                            let line =
+                                render_message(message, |room| room_label(backend.as_ref(), room));
+                            let marker = if message.id == id { ">> " } else { "   " };
+                            println!("{}{}", marker, line);
+                        }
+                    }
+                    Err(e) => println!("[system]: {:#}", e),
+                }
+                continue;
+            }
+
+            if msg == "/history" || msg.starts_with("/history ") {
+                let arg = msg.trim_start_matches("/history").trim();
+                let n = match arg {
+                    "" => 20,
+                    arg => match arg.parse::<usize>() {
+                        Ok(n) => n,
+                        Err(_) => {
+                            println!("[system]: usage: /history [count]");
+                            continue;
+                        }
+                    },
+                };
+
+                for entry in transcript.recent_threaded(n) {
+                    println!(
+                        "{}",
+                        render_entry(entry, |room| room_label(backend.as_ref(), room))
+                    );
+                }
+                continue;
+            }
+
+            match composer.confirm(msg) {
+                Composed::Send(body) => {
+                    let thread = current_thread.as_ref();
+                    send_and_echo(
+                        backend.as_mut(),
+                        &mut transcript,
+                        &current_room,
+                        thread,
+                        &body,
+                    )
+                    .await;
+                }
+                Composed::Note(question) => println!("[system]: {}", question),
+                _ => {}
+            }
+        }
+
+        let idle = session
+            .away_after
+            .is_some_and(|after| last_input.elapsed() >= after);
+        if idle && presence.state == PresenceState::Online {
+            presence = Presence::away(None);
+            auto_away = true;
+            auto_replied.clear();
+            // not worth an error line when the backend has no way to say so
+            if backend.set_presence(&presence).await.is_ok() {
+                println!("[system]: idle, now shown as away until you type something");
+            }
+        }
+
+        // this grabs all the events that have piled up since the last iteration
+        let events = backend.poll_events().await?;
+        // the status line and the half-typed line get in the way of output; they're
+        // put back below it afterwards
+        let mut redraw = !events.is_empty();
+        if redraw {
+            erase_below_output(&status, &draft);
+        }
+        // whether a message from someone else just showed up in the room we're in
+        let mut shown_here = false;
+        // this loops throug said events and prints them
+        for ev in events {
+            match &ev {
+                ChatEvent::Message(message) => {
+                    transcript.push_received(message.clone());
+                    // what they were typing has arrived
+                    if let Some(users) = typing.get_mut(&message.room) {
+                        users.retain(|user| *user != message.from);
+                    }
+                    shown_here |= message.room == current_room
+                        && matches!(message.origin, MessageOrigin::Remote { .. });
+
+                    // e.g. "bob (away)", as things stood when the message arrived
+                    let shown = match presence_of.get(&message.from) {
+                        Some(known) if known.state != PresenceState::Online => ChatMessage {
+                            from: format!("{} ({})", message.from, known.state),
+                            ..message.clone()
+                        },
+                        _ => message.clone(),
+                    };
+
+                    // outside the thread, a reply only shows as a short line under its root
+                    let line = match &message.thread {
+                        Some(root) if current_thread.as_ref() != Some(root) => {
+                            render_folded(&shown, transcript.thread_len(root))
+                        }
+                        _ => render_message(&shown, |room| room_label(backend.as_ref(), room)),
+                    };
+
+                    match message.attention {
+                        Attention::Highlight => {
+                            println!("{}", highlight(&line));
+                            send_alert(
+                                session.alert,
+                                message,
+                                &room_label(backend.as_ref(), &message.room),
+                            );
+                        }
+                        // what arrives in the room we're looking at is seen already
+                        Attention::Notify if message.room != current_room => {
+                            println!("{}", line);
+                            send_alert(
+                                session.alert,
+                                message,
+                                &room_label(backend.as_ref(), &message.room),
+                            );
+                        }
+                        _ => println!("{}", line),
+                    }
+
+                    // notices are what bots and other auto-replies send, so never answer one
+                    let answer = session.auto_reply
+                        && presence.state == PresenceState::Away
+                        && matches!(message.origin, MessageOrigin::Remote { .. })
+                        && message.kind != MessageKind::Notice
+                        && backend.is_direct(&message.room)
+                        && auto_replied.insert(message.from.clone());
+                    if answer {
+                        let body = match &presence.message {
+                            Some(text) => format!("I'm away: {}", text),
+                            None => "I'm away at the moment".to_string(),
+                        };
+                        match backend.send_notice(&message.room, &body).await {
+                            Ok(id) => {
+                                let mut echo =
+                                    local_echo(backend.as_ref(), id, &message.room, &body);
+                                echo.kind = MessageKind::Notice;
+                                println!(
+                                    "{}",
+                                    render_message(&echo, |room| room_label(
+                                        backend.as_ref(),
+                                        room
+                                    ))
+                                );
+                                transcript.push_local(id, echo);
+                            }
+                            Err(e) => println!("[system]: auto-reply failed: {:#}", e),
+                        }
+                    }
+                    continue;
+                }
+                ChatEvent::Reaction {
+                    id,
+                    user,
+                    key,
+                    added,
+                    ..
+                } => {
+                    let line = render_event(&ev, |room| room_label(backend.as_ref(), room));
+                    // the message's line is long gone off screen, so the new tally follows
+                    match transcript.apply_reaction(id, user, key, *added) {
+                        Some(entry) => println!(
+                            "{}, now {}",
+                            line,
+                            entry
+                                .reaction_summary()
+                                .unwrap_or_else(|| "no reactions".to_string())
+                        ),
+                        None => println!("{}", line),
+                    }
+                    continue;
+                }
+                ChatEvent::Receipt { room, user, id } => {
+                    // shown in /history rather than as a line per message read
+                    transcript.apply_receipt(room, user, id);
+                    continue;
+                }
+                ChatEvent::Presence { user, presence } => {
+                    // shown next to their messages and in /members, rather than as lines
+                    // of its own: a big server reports a lot of comings and goings
+                    presence_of.insert(user.clone(), presence.clone());
+                    continue;
+                }
+                // the message's line is long gone off screen, so it's shown again as it
+                // now reads; a message we don't have has nothing to show
+                ChatEvent::Edited {
+                    room,
+                    id,
+                    from,
+                    origin,
+                    body,
+                    formatted,
+                    ..
+                } => {
+                    if let Some(entry) =
+                        transcript.apply_edit(room, id, from, origin, body, formatted.as_ref())
+                    {
+                        println!(
+                            "{}",
+                            render_entry(entry, |room| room_label(backend.as_ref(), room))
+                        );
+                    }
+                    continue;
+                }
+                ChatEvent::Redacted { room, id, .. } => {
+                    if let Some(entry) = transcript.apply_redaction(room, id) {
+                        println!(
+                            "{}",
+                            render_entry(entry, |room| room_label(backend.as_ref(), room))
+                        );
+                    }
+                    continue;
+                }
+                ChatEvent::Typing { room, users } => {
+                    // shown in the status line rather than as a line of its own
+                    typing.insert(room.clone(), users.clone());
+                    continue;
+                }
+                ChatEvent::SendStatus { id, status, .. } => {
+                    let echoed = transcript.update(id, status).is_some();
+                    // the echo already stands for the message, so only failures need a line
+                    if echoed && !matches!(status, SendStatus::Failed { .. }) {
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+
+            println!(
+                "{}",
+                render_event(&ev, |room| room_label(backend.as_ref(), room))
+            );
+        }
+
+        // one receipt for everything shown, however many messages that was
+        if shown_here {
+            mark_read(backend.as_mut(), &transcript, &current_room).await;
+        }
+
+        let wanted = typing
+            .get(&current_room)
+            .and_then(|users| typing_status(users))
+            .filter(|_| has_status_line)
+            .map(|text| format!("[typing]: {}", text));
+        if !redraw && wanted != status {
+            erase_below_output(&status, &draft);
+            redraw = true;
+        }
+        if redraw {
+            status = wanted;
+            match &status {
+                Some(shown) => print!("{}\n{}", shown, draft),
+                None => print!("{}", draft),
+            }
+            let _ = std::io::stdout().flush();
+        }
+
+        sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Clears the status line and the draft off the bottom of the screen, if either is
+/// showing, so output can go where they were
+fn erase_below_output(status: &Option<String>, draft: &str) {
+    match status {
+        Some(shown) => print!("{}", input::erase(&format!("{}\n{}", shown, draft))),
+        None if !draft.is_empty() => print!("{}", input::erase(draft)),
+        None => {}
+    }
+}
+
+/// Parses the arguments of `/nick [--room] <name>` into the name and whether it's
+/// only for the current room
+fn parse_nick(args: &str) -> Option<(&str, bool)> {
+    let args = args.trim();
+    let (name, room_only) = match args.strip_prefix("--room") {
+        Some(name) if name.is_empty() || name.starts_with(char::is_whitespace) => {
+            (name.trim(), true)
+        }
+        _ => (args, false),
+    };
+
+    (!name.is_empty()).then_some((name, room_only))
+}
+
+/// Parses the arguments of `/create <name> [--public|--private] [--encrypted]`;
+/// every word that isn't a flag is part of the room name
+fn parse_new_room(args: &str) -> Result<NewRoom, String> {
+    let mut name = Vec::new();
+    let mut public = None;
+    let mut encrypted = false;
+
+    for word in args.split_whitespace() {
+        match word {
+            "--public" | "--private" => {
+                let wants_public = word == "--public";
+                if public.is_some_and(|p| p != wants_public) {
+                    return Err("--public and --private can't be combined".to_string());
+                }
+                public = Some(wants_public);
+            }
+            "--encrypted" => encrypted = true,
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ => name.push(word),
+        }
+    }
+
+    if name.is_empty() {
+        return Err("missing room name".to_string());
+    }
+
+    Ok(NewRoom {
+        name: name.join(" "),
+        public: public.unwrap_or(false),
+        encrypted,
+    })
+}
+
+/// Parses the arguments of `/directory [query] [--server <server>]` into
+/// an optional search term and an optional server to search instead of our own
+fn parse_directory_args(args: &str) -> Result<(Option<String>, Option<String>), String> {
+    let mut query = Vec::new();
+    let mut server = None;
+    let mut words = args.split_whitespace();
+
+    while let Some(word) = words.next() {
+        if word == "--server" {
+            let Some(name) = words.next() else {
+                return Err("--server needs a server name".to_string());
+            };
+            server = Some(name.to_string());
+        } else {
+            query.push(word);
+        }
+    }
+
+    let query = (!query.is_empty()).then(|| query.join(" "));
+
+    Ok((query, server))
+}
+
+/// Parses the arguments of `/search <terms> [--room <room>]` into the search
+/// terms and an optional room to limit the search to
+fn parse_search_args(args: &str) -> Result<(String, Option<String>), String> {
+    let mut terms = Vec::new();
+    let mut room = None;
+    let mut words = args.split_whitespace();
+
+    while let Some(word) = words.next() {
+        if word == "--room" {
+            let Some(name) = words.next() else {
+                return Err("--room needs a room".to_string());
+            };
+            room = Some(name.to_string());
+        } else {
+            terms.push(word);
+        }
+    }
+
+    if terms.is_empty() {
+        return Err("nothing to search for".to_string());
+    }
+
+    Ok((terms.join(" "), room))
+}
+
+/// Splits `<message-id> <text>` arguments, both parts required
+fn split_id(args: &str) -> Option<(&str, &str)> {
+    let (id, text) = args.trim().split_once(char::is_whitespace)?;
+    let text = text.trim();
+    (!text.is_empty()).then_some((id, text))
+}
+
+/// Splits `<target> [reason]` arguments, e.g. for /kick and /delete
+fn split_reason(args: &str) -> (&str, Option<&str>) {
+    match split_id(args) {
+        Some((target, reason)) => (target, Some(reason)),
+        None => (args.trim(), None),
+    }
+}
+
+/// Parses the arguments of `/room <setting> <value>`
+fn parse_room_setting(args: &str) -> Result<RoomSetting, String> {
+    let (setting, value) = args
+        .trim()
+        .split_once(char::is_whitespace)
+        .map(|(setting, value)| (setting, value.trim()))
+        .ok_or_else(|| "missing value".to_string())?;
+
+    match setting {
+        "name" => Ok(RoomSetting::Name(value.to_string())),
+        "topic" => Ok(RoomSetting::Topic(value.to_string())),
+        "avatar" => Ok(RoomSetting::Avatar(PathBuf::from(value))),
+        "join-rule" => match value {
+            "public" => Ok(RoomSetting::JoinRule(JoinRule::Public)),
+            "invite" => Ok(RoomSetting::JoinRule(JoinRule::Invite)),
+            "knock" => Ok(RoomSetting::JoinRule(JoinRule::Knock)),
+            other => Err(format!("unknown join rule {}", other)),
+        },
+        "history" => match value {
+            "world-readable" => Ok(RoomSetting::HistoryVisibility(
+                HistoryVisibility::WorldReadable,
+            )),
+            "shared" => Ok(RoomSetting::HistoryVisibility(HistoryVisibility::Shared)),
+            "invited" => Ok(RoomSetting::HistoryVisibility(HistoryVisibility::Invited)),
+            "joined" => Ok(RoomSetting::HistoryVisibility(HistoryVisibility::Joined)),
+            other => Err(format!("unknown history visibility {}", other)),
+        },
+        other => Err(format!("unknown room setting {}", other)),
+    }
+}
+
+/// Sends `body` to `room`, or to `thread` in it, and shows it straight away; the
+/// backend's SendStatus events confirm or fail it later
+async fn send_and_echo(
+    backend: &mut dyn ChatBackend,
+    transcript: &mut Transcript,
+    room: &RoomId,
+    thread: Option<&MessageId>,
+    body: &str,
+) {
+    // a failed send is reported, never fatal: the connection may well recover
+    let sent = match thread {
+        Some(root) => backend.send_to_thread(room, root, None, body).await,
+        None => backend.send_message(room, body).await,
+    };
+    let id = match sent {
+        Ok(id) => id,
+        Err(e) => {
+            println!("[system]: send failed: {:#}", e);
+            return;
+        }
+    };
+
+    let mut echo = local_echo(backend, id, room, body);
+    echo.thread = thread.cloned();
+    println!(
+        "{}",
+        render_message(&echo, |room| room_label(backend, room))
+    );
+    transcript.push_local(id, echo);
+}
+
+/// What we show for a message we just sent, until the backend confirms it
+fn local_echo(backend: &dyn ChatBackend, id: Uuid, room: &RoomId, body: &str) -> ChatMessage {
+    ChatMessage {
+        id: id.into(),
+        ts: Utc::now(),
+        from: backend.own_user(),
+        room: room.clone(),
+        body: body.to_string(),
+        formatted: RichText::from_markdown(body),
+        kind: MessageKind::Text,
+        attachment: None,
+        origin: MessageOrigin::Local,
+        in_reply_to: None,
+        thread: None,
+        attention: Attention::None,
+    }
+}
+
+/// The backend's name for a room when it has one, otherwise the id itself
+fn room_label(backend: &dyn ChatBackend, room: &RoomId) -> String {
+    backend.room_name(room).unwrap_or_else(|| room.to_string())
+}
+
+fn format_ts(ts: &DateTime<Utc>) -> String {
+    ts.with_timezone(&Local)
+        .format("%m/%d/%Y %H:%M")
+        .to_string()
+}
+
+/// A scrollback line for /history and /thread, with its edit, deletion and send state
+fn render_entry(entry: &Entry, room_label: impl Fn(&RoomId) -> String) -> String {
+    let mut line = render_message(&entry.message, room_label);
+
+    if entry.deleted {
+        line.push_str(" (deleted)");
+    } else if entry.edited {
+        line.push_str(" (edited)");
+    }
+
+    if !entry.read_by.is_empty() {
+        line.push_str(&format!(" (read by {})", entry.read_by.join(", ")));
+    }
+
+    match &entry.status {
+        Some(SendStatus::Pending) => line.push_str(" (sending)"),
+        Some(SendStatus::Failed { error }) => line.push_str(&format!(" (failed: {})", error)),
+        _ => {}
+    }
+
+    // on a line of their own, under the message
+    if let Some(summary) = entry.reaction_summary() {
+        let indent = if entry.message.thread.is_some() {
+            "      "
+        } else {
+            "    "
+        };
+        line.push_str(&format!("\n{}{}", indent, summary));
+    }
+
+    line
+}
+
+/// Marks `room` read up to the newest message someone else sent there, now that
+/// it's on screen
+async fn mark_read(backend: &mut dyn ChatBackend, transcript: &Transcript, room: &RoomId) {
+    if let Some(entry) = transcript.last_received(room) {
+        // a lost receipt only leaves the room unread a little longer
+        let _ = backend.send_receipt(room, &entry.message.id).await;
+    }
+}
+
+/// How many characters of a message /search shows
+const SNIPPET_LEN: usize = 60;
+
+/// Up to `width` characters of `body` around the first of the search `terms` it
+/// contains, with `…` wherever the text was cut
+fn snippet(body: &str, terms: &str, width: usize) -> String {
+    // a snippet is one line, however many the message has
+    let body: Vec<char> = body.replace('\n', " ").chars().collect();
+    if body.len() <= width {
+        return body.into_iter().collect();
+    }
+
+    let lower: String = body.iter().collect::<String>().to_lowercase();
+    let first_match = terms
+        .split_whitespace()
+        .filter_map(|term| lower.find(&term.to_lowercase()))
+        .min()
+        // a byte offset into `lower`; lowercasing can change lengths, so this is approximate
+        .map(|offset| lower[..offset].chars().count())
+        .unwrap_or(0);
+
+    let start = first_match
+        .saturating_sub(width / 3)
+        .min(body.len() - width);
+    let end = start + width;
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.extend(&body[start..end]);
+    if end < body.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Makes a line that mentions us stand out: reverse video on a terminal, and a
+/// marker where escape codes would only be noise (e.g. output piped to a file)
+fn highlight(line: &str) -> String {
+    if std::io::stdout().is_terminal() {
+        format!("\x1b[7m{}\x1b[0m", line)
+    } else {
+        format!("(!) {}", line)
+    }
+}
+
+/// Rings the terminal bell or raises a desktop notification, as chosen with `--alert`
+fn send_alert(alert: Alert, message: &ChatMessage, room_label: &str) {
+    let sequence = match alert {
+        Alert::Off => return,
+        Alert::Bell => "\x07".to_string(),
+        Alert::Osc9 => osc9(&format!(
+            "{} in {}: {}",
+            message.from, room_label, message.body
+        )),
+    };
+
+    let mut stdout = std::io::stdout();
+    let _ = stdout.write_all(sequence.as_bytes());
+    let _ = stdout.flush();
+}
+
+/// An OSC 9 desktop notification. Control characters are dropped, so a message
+/// can't end the sequence early and have the rest interpreted by the terminal.
+fn osc9(text: &str) -> String {
+    const MAX_LEN: usize = 200;
+
+    let text: String = text
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_LEN)
+        .collect();
+    format!("\x1b]9;{}\x07", text)
+}
+
+/// How a thread reply shows in the room while we're not in its thread
+fn render_folded(message: &ChatMessage, replies: usize) -> String {
+    const PREVIEW_LEN: usize = 60;
+
+    let root = message
+        .thread
+        .as_ref()
+        .map(MessageId::as_str)
+        .unwrap_or("?");
+    let mut preview: String = message
+        .body
+        .replace('\n', " ")
+        .chars()
+        .take(PREVIEW_LEN)
+        .collect();
+    if message.body.chars().count() > PREVIEW_LEN {
+        preview.push('…');
+    }
+
+    format!(
+        "  ↳ thread {} ({} {}) {}: {}",
+        root,
+        replies,
+        if replies == 1 { "reply" } else { "replies" },
+        message.from,
+        preview
+    )
+}
+
+fn render_message(message: &ChatMessage, room_label: impl Fn(&RoomId) -> String) -> String {
+    let ChatMessage {
+        id,
+        ts,
+        room,
+        from,
+        body,
+        formatted,
+        kind,
+        attachment,
+        origin,
+        in_reply_to,
+        ..
+    } = message;
+
+    let mut prefix = format!("{} | {} [{}]", format_ts(ts), id, room_label(room));
+    // thread replies sit indented under their root
+    if message.thread.is_some() {
+        prefix.insert_str(0, "  ↳ ");
+    }
+
+    // our own messages sent from elsewhere, e.g. "me (via ABCDEFGH)"
+    let from = match origin {
+        MessageOrigin::Remote { .. } | MessageOrigin::Local => from.clone(),
+        MessageOrigin::OwnDevice {
+            device: Some(device),
+        } => {
+            format!("{} (via {})", from, device)
+        }
+        MessageOrigin::OwnDevice { device: None } => {
+            format!("{} (via another device)", from)
+        }
+    };
+
+    // e.g. "bob (reply to $abc)"
+    let from = match in_reply_to {
+        Some(to) => format!("{} (reply to {})", from, to),
+        None => from,
+    };
+
+    if let Some(attachment) = attachment {
+        return if body.is_empty() {
+            format!("{} {} sent {}", prefix, from, attachment)
+        } else {
+            format!("{} {} sent {}: {}", prefix, from, attachment, body)
+        };
+    }
+
+    let body = shown_body(body, formatted.as_ref());
+    match kind {
+        MessageKind::Text => format!("{} {}: {}", prefix, from, body),
+        MessageKind::Notice => format!("{} {} (notice): {}", prefix, from, body),
+        MessageKind::Emote => format!("{} * {} {}", prefix, from, body),
+    }
+}
+
+/// A message body as it shows here: styled if it has formatting and the terminal
+/// takes it, plain otherwise. Lines after the first are indented, so they read as
+/// part of the message rather than lines of their own.
+fn shown_body(body: &str, formatted: Option<&RichText>) -> String {
+    let text = match formatted {
+        Some(formatted) if style::enabled() => style::styled(formatted),
+        _ => body.to_string(),
+    };
+    text.replace('\n', "\n    ")
+}
+
+fn render_event(ev: &ChatEvent, room_label: impl Fn(&RoomId) -> String) -> String {
+    match ev {
+        ChatEvent::Message(message) => render_message(message, room_label),
+        ChatEvent::Edited {
+            ts,
+            room,
+            id,
+            from,
+            body,
+            formatted,
+            ..
+        } => format!(
+            "{} | {} [{}] {} edited: {}",
+            format_ts(ts),
+            id,
+            room_label(room),
+            from,
+            shown_body(body, formatted.as_ref())
+        ),
+        ChatEvent::Redacted {
+            ts,
+            room,
+            id,
+            by,
+            reason,
+        } => {
+            let reason = reason
+                .as_ref()
+                .map(|r| format!(" ({})", r))
+                .unwrap_or_default();
+            format!(
+                "{} | {} [{}] deleted by {}{}",
+                format_ts(ts),
+                id,
+                room_label(room),
+                by,
+                reason
+            )
+        }
+        ChatEvent::Membership {
+            ts,
+            room,
+            user,
+            change,
+        } => {
+            let reason = |reason: &Option<String>| {
+                reason
+                    .as_ref()
+                    .map(|r| format!(" ({})", r))
+                    .unwrap_or_default()
+            };
+
+            let what = match change {
+                MembershipChange::Joined => "joined".to_string(),
+                MembershipChange::Left => "left".to_string(),
+                MembershipChange::Invited { by } => format!("was invited by {}", by),
+                MembershipChange::Kicked { by, reason: r } => {
+                    format!("was kicked by {}{}", by, reason(r))
+                }
+                MembershipChange::Banned { by, reason: r } => {
+                    format!("was banned by {}{}", by, reason(r))
+                }
+                MembershipChange::Unbanned { by } => format!("was unbanned by {}", by),
+                MembershipChange::InviteRejected => "rejected the invite".to_string(),
+                MembershipChange::InviteRevoked { by } => {
+                    format!("had their invite revoked by {}", by)
+                }
+                MembershipChange::Renamed { to } => format!("is now known as {}", to),
+            };
+
+            format!(
+                "{} | [{}] {} {}",
+                format_ts(ts),
+                room_label(room),
+                user,
+                what
+            )
+        }
+        ChatEvent::Invite(invite) => format!(
+            "[system]: invited to {} - /accept {} or /decline {}",
+            invite, invite.room, invite.room
+        ),
+        ChatEvent::Connection(status) => format!("[system]: {}", status),
+        ChatEvent::SendStatus { id, room, status } => match status {
+            SendStatus::Pending => format!("[system]: sending {} to {}", id, room_label(room)),
+            SendStatus::Sent { remote_id, .. } => format!("[system]: sent {} as {}", id, remote_id),
+            SendStatus::Failed { error } => format!(
+                "[system]: failed to send {} ({}) - /retry {} to try again",
+                id, error, id
+            ),
+        },
+        ChatEvent::Reaction {
+            room,
+            id,
+            user,
+            key,
+            added,
+        } => {
+            let what = if *added { "reacted" } else { "took back" };
+            let on = if *added { "to" } else { "on" };
+            format!(
+                "[{}] {} {} {} {} {}",
+                room_label(room),
+                user,
+                what,
+                key,
+                on,
+                id
+            )
+        }
+        ChatEvent::Receipt { room, user, id } => {
+            format!("[system]: {} read {} up to {}", user, room_label(room), id)
+        }
+        ChatEvent::Presence { user, presence } => format!("[system]: {} is {}", user, presence),
+        ChatEvent::Typing { users, .. } => format!(
+            "[typing]: {}",
+            typing_status(users).unwrap_or_else(|| "nobody is typing".to_string())
+        ),
+        ChatEvent::System(text) => format!("[system]: {}", text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_args_join_words_into_name_and_default_to_private() {
+        let room = parse_new_room("team chat --encrypted").unwrap();
+
+        assert_eq!(room.name, "team chat");
+        assert!(!room.public);
+        assert!(room.encrypted);
+    }
+
+    #[test]
+    fn create_args_reject_conflicting_or_unknown_flags() {
+        assert!(parse_new_room("team --public --private").is_err());
+        assert!(parse_new_room("team --secret").is_err());
+        assert!(parse_new_room("--public").is_err());
+    }
+
+    #[test]
+    fn nick_args_need_a_name_with_or_without_room_flag() {
+        assert_eq!(parse_nick(" Ada "), Some(("Ada", false)));
+        assert_eq!(parse_nick("--room  Ada L"), Some(("Ada L", true)));
+        assert_eq!(parse_nick("--roomba"), Some(("--roomba", false)));
+        assert_eq!(parse_nick("--room"), None);
+        assert_eq!(parse_nick("--room   "), None);
+    }
+
+    #[test]
+    fn message_id_args_need_both_id_and_text() {
+        assert_eq!(split_id(" $abc  fixed text "), Some(("$abc", "fixed text")));
+        assert_eq!(split_id("$abc"), None);
+        assert_eq!(split_id("$abc   "), None);
+    }
+
+    #[test]
+    fn room_setting_args_pick_setting_and_value() {
+        assert_eq!(
+            parse_room_setting("topic  Rust talk, mostly ").unwrap(),
+            RoomSetting::Topic("Rust talk, mostly".to_string())
+        );
+        assert_eq!(
+            parse_room_setting("history world-readable").unwrap(),
+            RoomSetting::HistoryVisibility(HistoryVisibility::WorldReadable)
+        );
+        assert!(parse_room_setting("join-rule anyone").is_err());
+        assert!(parse_room_setting("colour blue").is_err());
+        assert!(parse_room_setting("name").is_err());
+
+        assert_eq!(split_reason("@bob:x spam"), ("@bob:x", Some("spam")));
+        assert_eq!(split_reason(" @bob:x "), ("@bob:x", None));
+    }
+
+    #[test]
+    fn search_args_need_terms_and_take_an_optional_room() {
+        assert_eq!(
+            parse_search_args("release notes --room #rust:matrix.org").unwrap(),
+            (
+                "release notes".to_string(),
+                Some("#rust:matrix.org".to_string())
+            )
+        );
+        assert_eq!(
+            parse_search_args(" borrow  checker ").unwrap(),
+            ("borrow checker".to_string(), None)
+        );
+        assert!(parse_search_args("--room #rust:matrix.org").is_err());
+        assert!(parse_search_args("release --room").is_err());
+    }
+
+    #[test]
+    fn snippets_centre_on_the_first_match() {
+        assert_eq!(snippet("short one", "one", 20), "short one");
+
+        let body = "a long message that eventually mentions the Borrow checker near its end";
+        assert_eq!(snippet(body, "borrow", 20), "…s the Borrow checker…");
+        assert_eq!(snippet(body, "nothing", 10), "a long mes…");
+        assert_eq!(snippet(body, "end", 10), "…ar its end");
+    }
+
+    #[test]
+    fn osc9_notifications_cannot_be_escaped_from() {
+        assert_eq!(osc9("bob: hi"), "\x1b]9;bob: hi\x07");
+        assert_eq!(
+            osc9("bob: \x07\x1b]2;pwned\x1b\\ hi\nthere"),
+            "\x1b]9;bob: ]2;pwned\\ hithere\x07"
+        );
+        assert_eq!(osc9(&"x".repeat(500)).len(), "\x1b]9;\x07".len() + 200);
+    }
+
+    #[test]
+    fn directory_args_split_query_from_server() {
+        assert_eq!(parse_directory_args("").unwrap(), (None, None));
+        assert_eq!(
+            parse_directory_args(" rust lang --server matrix.org").unwrap(),
+            (
+                Some("rust lang".to_string()),
+                Some("matrix.org".to_string())
+            )
+        );
+        assert!(parse_directory_args("rust --server").is_err());
+    }
+}
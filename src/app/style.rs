@@ -0,0 +1,99 @@
+use std::io::IsTerminal;
+
+use crate::protocol::{Block, Inline, RichText};
+
+const BOLD: (&str, &str) = ("\x1b[1m", "\x1b[22m");
+const ITALIC: (&str, &str) = ("\x1b[3m", "\x1b[23m");
+const UNDERLINE: (&str, &str) = ("\x1b[4m", "\x1b[24m");
+const CODE: (&str, &str) = ("\x1b[36m", "\x1b[39m");
+const GUTTER: &str = "\x1b[2m│\x1b[22m";
+
+/// Whether messages show their formatting: only on a terminal, and not if the user
+/// asked for no colour with `NO_COLOR`. Otherwise they get the plain body.
+pub fn enabled() -> bool {
+    std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+}
+
+/// `text` with terminal styling: bold, italics, code in colour and links as OSC 8
+/// hyperlinks. A code block always starts on a line of its own, behind a gutter.
+pub fn styled(text: &RichText) -> String {
+    let mut out = String::new();
+
+    for (n, block) in text.blocks.iter().enumerate() {
+        match block {
+            Block::Paragraph(inlines) => {
+                if n > 0 {
+                    out.push('\n');
+                }
+                styled_inlines(&mut out, inlines);
+            }
+            Block::Code { text, .. } => {
+                for line in text.split('\n') {
+                    out.push_str(&format!(
+                        "\n  {} {}{}{}",
+                        GUTTER,
+                        CODE.0,
+                        clean(line),
+                        CODE.1
+                    ));
+                }
+            }
+        }
+    }
+
+    out
+}
+
+fn styled_inlines(out: &mut String, inlines: &[Inline]) {
+    let wrap = |out: &mut String, (on, off): (&str, &str), inner: &[Inline]| {
+        out.push_str(on);
+        styled_inlines(out, inner);
+        out.push_str(off);
+    };
+
+    for inline in inlines {
+        match inline {
+            Inline::Text(text) => out.push_str(&clean(text)),
+            Inline::Bold(inner) => wrap(out, BOLD, inner),
+            Inline::Italic(inner) => wrap(out, ITALIC, inner),
+            Inline::Code(text) => out.push_str(&format!("{}{}{}", CODE.0, clean(text), CODE.1)),
+            Inline::Link { url, text } => {
+                out.push_str(&format!("\x1b]8;;{}\x1b\\", clean(url)));
+                wrap(out, UNDERLINE, text);
+                out.push_str("\x1b]8;;\x1b\\");
+            }
+            Inline::LineBreak => out.push('\n'),
+        }
+    }
+}
+
+/// Drops control characters, so a message can't slip its own escape codes in
+/// between ours
+fn clean(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_become_hyperlinks_that_cannot_be_escaped_from() {
+        let text = RichText::from_markdown("see [*the* docs](https://a.org/\x1b]2;x)").unwrap();
+
+        assert_eq!(
+            styled(&text),
+            "see \x1b]8;;https://a.org/]2;x\x1b\\\x1b[4m\x1b[3mthe\x1b[23m docs\x1b[24m\x1b]8;;\x1b\\"
+        );
+    }
+
+    #[test]
+    fn code_blocks_sit_on_their_own_lines() {
+        let text = RichText::from_markdown("**run**\n```\nmake\nmake test\n```").unwrap();
+
+        assert_eq!(
+            styled(&text),
+            "\x1b[1mrun\x1b[22m\n  \x1b[2m│\x1b[22m \x1b[36mmake\x1b[39m\n  \x1b[2m│\x1b[22m \x1b[36mmake test\x1b[39m"
+        );
+    }
+}
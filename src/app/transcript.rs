@@ -0,0 +1,560 @@
+use std::collections::VecDeque;
+
+use uuid::Uuid;
+
+use crate::protocol::{ChatMessage, MessageId, MessageOrigin, RichText, RoomId, SendStatus};
+
+/// How many messages the session keeps for `/history`
+pub const SCROLLBACK_LEN: usize = 1000;
+
+/// One line of scrollback: a received message, or the local echo of one we sent
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub message: ChatMessage,
+    /// `None` for received messages, otherwise how far our send has got
+    pub status: Option<SendStatus>,
+    /// what `send_message` returned for a local echo; the message id becomes the
+    /// backend's own once the send is confirmed
+    pub local_id: Option<Uuid>,
+    pub edited: bool,
+    pub deleted: bool,
+    /// others whose read marker in the room is at this message
+    pub read_by: Vec<String>,
+    /// in the order each key was first used
+    pub reactions: Vec<ReactionCount>,
+}
+
+impl Entry {
+    /// e.g. "👍 3  🎉 1", or `None` if nobody has reacted
+    pub fn reaction_summary(&self) -> Option<String> {
+        if self.reactions.is_empty() {
+            return None;
+        }
+
+        let counts: Vec<String> = self
+            .reactions
+            .iter()
+            .map(|reaction| format!("{} {}", reaction.key, reaction.users.len()))
+            .collect();
+        Some(counts.join("  "))
+    }
+}
+
+/// One reaction key on a message, usually an emoji, and who reacted with it
+#[derive(Debug, Clone)]
+pub struct ReactionCount {
+    pub key: String,
+    pub users: Vec<String>,
+}
+
+/// Both sides of the conversation, oldest first, capped at a fixed length
+#[derive(Debug)]
+pub struct Transcript {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+}
+
+impl Transcript {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn push_received(&mut self, message: ChatMessage) {
+        self.push(Entry {
+            message,
+            status: None,
+            local_id: None,
+            edited: false,
+            deleted: false,
+            read_by: Vec::new(),
+            reactions: Vec::new(),
+        });
+    }
+
+    /// records a message we just handed to the backend, before it's confirmed
+    pub fn push_local(&mut self, local_id: Uuid, message: ChatMessage) {
+        self.push(Entry {
+            message,
+            status: Some(SendStatus::Pending),
+            local_id: Some(local_id),
+            edited: false,
+            deleted: false,
+            read_by: Vec::new(),
+            reactions: Vec::new(),
+        });
+    }
+
+    fn push(&mut self, entry: Entry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// applies a backend's `SendStatus` to the local echo it belongs to, returning
+    /// that entry, or `None` if the message has scrolled out or was never echoed
+    pub fn update(&mut self, id: &Uuid, status: &SendStatus) -> Option<&Entry> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.local_id == Some(*id))?;
+
+        match (status, &entry.status) {
+            // the event id and the server timestamp can arrive separately, in either order
+            (SendStatus::Sent { ts: None, .. }, Some(SendStatus::Sent { ts: Some(_), .. })) => {}
+            (SendStatus::Sent { remote_id, ts }, _) => {
+                entry.message.id = remote_id.clone();
+                if let Some(ts) = ts {
+                    entry.message.ts = *ts;
+                }
+                entry.status = Some(status.clone());
+            }
+            // a late Pending (e.g. from a retry) never undoes a confirmation
+            (SendStatus::Pending, Some(SendStatus::Sent { .. })) => {}
+            _ => entry.status = Some(status.clone()),
+        }
+
+        Some(entry)
+    }
+
+    /// replaces the body of message `id` in `room` after its sender edited it. An
+    /// edit from anyone else is ignored: our own messages only take our own edits,
+    /// and anyone else's only edits under the same name.
+    pub fn apply_edit(
+        &mut self,
+        room: &RoomId,
+        id: &MessageId,
+        from: &str,
+        origin: &MessageOrigin,
+        body: &str,
+        formatted: Option<&RichText>,
+    ) -> Option<&Entry> {
+        let entry = self.find_mut(id).filter(|entry| {
+            let sender = &entry.message;
+            sender.room == *room
+                && match (sender.origin.is_own(), origin.is_own()) {
+                    (true, true) => true,
+                    (false, false) => sender.from == from,
+                    _ => false,
+                }
+        })?;
+        entry.message.body = body.to_string();
+        entry.message.formatted = formatted.cloned();
+        entry.edited = true;
+        Some(entry)
+    }
+
+    /// blanks message `id` in `room` after it was deleted, keeping its place in the
+    /// scrollback. Only a message that made it to the room can be deleted: an echo
+    /// still sending, or that failed to, is left alone.
+    pub fn apply_redaction(&mut self, room: &RoomId, id: &MessageId) -> Option<&Entry> {
+        let entry = self.find_mut(id).filter(|entry| {
+            entry.message.room == *room
+                && !matches!(
+                    entry.status,
+                    Some(SendStatus::Pending | SendStatus::Failed { .. })
+                )
+        })?;
+        entry.message.body.clear();
+        entry.message.attachment = None;
+        entry.deleted = true;
+        Some(entry)
+    }
+
+    /// adds or takes back `user`'s `key` reaction on message `id`, returning that entry
+    pub fn apply_reaction(
+        &mut self,
+        id: &MessageId,
+        user: &str,
+        key: &str,
+        added: bool,
+    ) -> Option<&Entry> {
+        let entry = self.find_mut(id)?;
+        let existing = entry
+            .reactions
+            .iter()
+            .position(|reaction| reaction.key == key);
+
+        match (existing, added) {
+            (Some(i), true) => {
+                let users = &mut entry.reactions[i].users;
+                if !users.iter().any(|reactor| reactor == user) {
+                    users.push(user.to_string());
+                }
+            }
+            (None, true) => entry.reactions.push(ReactionCount {
+                key: key.to_string(),
+                users: vec![user.to_string()],
+            }),
+            (Some(i), false) => {
+                entry.reactions[i].users.retain(|reactor| reactor != user);
+                if entry.reactions[i].users.is_empty() {
+                    entry.reactions.remove(i);
+                }
+            }
+            (None, false) => {}
+        }
+
+        Some(entry)
+    }
+
+    /// moves `user`'s read marker in `room` to message `id`, returning that entry; a
+    /// marker on a message we don't have stays where it was, which is still true
+    pub fn apply_receipt(&mut self, room: &RoomId, user: &str, id: &MessageId) -> Option<&Entry> {
+        self.find_mut(id)
+            .filter(|entry| entry.message.room == *room)?;
+
+        for entry in self.entries.iter_mut() {
+            if entry.message.room == *room {
+                entry.read_by.retain(|reader| reader != user);
+            }
+        }
+
+        let entry = self.find_mut(id)?;
+        entry.read_by.push(user.to_string());
+        Some(entry)
+    }
+
+    /// the newest message someone else sent to `room`, the one to mark it read up to
+    pub fn last_received(&self, room: &RoomId) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.status.is_none() && entry.message.room == *room)
+    }
+
+    /// the backend's id for a message typed by the user, who may only have seen the
+    /// local id of something they sent
+    pub fn resolve(&self, typed: &str) -> MessageId {
+        let local = Uuid::parse_str(typed).ok();
+
+        self.entries
+            .iter()
+            .find(|entry| local.is_some() && entry.local_id == local)
+            .map(|entry| entry.message.id.clone())
+            .unwrap_or_else(|| MessageId::new(typed))
+    }
+
+    /// how many replies to the thread under `root` we've seen
+    pub fn thread_len(&self, root: &MessageId) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.message.thread.as_ref() == Some(root))
+            .count()
+    }
+
+    /// the root of a thread, if we still have it, followed by the replies we've seen
+    pub fn thread<'a>(&'a self, root: &'a MessageId) -> impl Iterator<Item = &'a Entry> {
+        self.entries.iter().filter(move |entry| {
+            entry.message.id == *root || entry.message.thread.as_ref() == Some(root)
+        })
+    }
+
+    /// the last `n` entries, oldest first, with each thread's replies moved up to sit
+    /// under their root
+    pub fn recent_threaded(&self, n: usize) -> Vec<&Entry> {
+        let window: Vec<&Entry> = self.recent(n).collect();
+        let has_root = |root: &MessageId| window.iter().any(|entry| entry.message.id == *root);
+
+        let mut lines = Vec::with_capacity(window.len());
+        for entry in &window {
+            match &entry.message.thread {
+                Some(root) if has_root(root) => {}
+                // the root has scrolled out of the window, so the reply stays where it is
+                Some(_) => lines.push(*entry),
+                None => {
+                    lines.push(*entry);
+                    lines.extend(
+                        window
+                            .iter()
+                            .filter(|reply| {
+                                reply.message.thread.as_ref() == Some(&entry.message.id)
+                            })
+                            .copied(),
+                    );
+                }
+            }
+        }
+
+        lines
+    }
+
+    fn find_mut(&mut self, id: &MessageId) -> Option<&mut Entry> {
+        self.entries
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.message.id == *id)
+    }
+
+    /// the last `n` entries, oldest first
+    pub fn recent(&self, n: usize) -> impl Iterator<Item = &Entry> {
+        self.entries
+            .iter()
+            .skip(self.entries.len().saturating_sub(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Attention, MessageKind, MessageOrigin, RoomId};
+    use chrono::{DateTime, TimeZone, Utc};
+
+    fn message(body: &str, ts: DateTime<Utc>) -> ChatMessage {
+        ChatMessage {
+            id: Uuid::new_v4().into(),
+            ts,
+            from: "me".to_string(),
+            room: RoomId::default(),
+            body: body.to_string(),
+            formatted: None,
+            kind: MessageKind::Text,
+            attachment: None,
+            origin: MessageOrigin::Local,
+            in_reply_to: None,
+            thread: None,
+            attention: Attention::None,
+        }
+    }
+
+    #[test]
+    fn confirmation_fills_in_remote_id_and_server_time() {
+        let local_ts = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let server_ts = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 3).unwrap();
+        let id = Uuid::new_v4();
+
+        let mut transcript = Transcript::new(10);
+        transcript.push_local(id, message("hi", local_ts));
+
+        let full = SendStatus::Sent {
+            remote_id: MessageId::new("$event"),
+            ts: Some(server_ts),
+        };
+        let without_ts = SendStatus::Sent {
+            remote_id: MessageId::new("$event"),
+            ts: None,
+        };
+
+        transcript.update(&id, &full).unwrap();
+        // the queue's confirmation can land after the synced one; it mustn't lose the timestamp
+        let entry = transcript.update(&id, &without_ts).unwrap();
+
+        assert_eq!(entry.message.ts, server_ts);
+        assert_eq!(entry.message.id, MessageId::new("$event"));
+        assert!(matches!(
+            entry.status,
+            Some(SendStatus::Sent { ts: Some(_), .. })
+        ));
+
+        // the local id the user saw still finds the message under its new id
+        assert_eq!(
+            transcript.resolve(&id.to_string()),
+            MessageId::new("$event")
+        );
+        assert_eq!(transcript.resolve("$other"), MessageId::new("$other"));
+    }
+
+    #[test]
+    fn failures_and_unknown_ids_only_touch_local_echoes() {
+        let mut transcript = Transcript::new(10);
+        let received_id = Uuid::new_v4();
+        let mut received = message("from them", Utc::now());
+        received.id = received_id.into();
+        transcript.push_received(received);
+
+        let failed = SendStatus::Failed {
+            error: "offline".to_string(),
+        };
+        assert!(transcript.update(&received_id, &failed).is_none());
+        assert!(transcript.update(&Uuid::new_v4(), &failed).is_none());
+
+        let id = Uuid::new_v4();
+        transcript.push_local(id, message("from me", Utc::now()));
+
+        let entry = transcript.update(&id, &failed).unwrap();
+        assert!(matches!(entry.status, Some(SendStatus::Failed { .. })));
+    }
+
+    #[test]
+    fn edits_and_deletions_update_the_original_entry() {
+        let mut transcript = Transcript::new(10);
+        let mut original = message("teh typo", Utc::now());
+        original.id = MessageId::new("$typo");
+        original.from = "them".to_string();
+        original.origin = MessageOrigin::default();
+        transcript.push_received(original);
+
+        let typo = MessageId::new("$typo");
+        let remote = MessageOrigin::default();
+        let own = MessageOrigin::OwnDevice { device: None };
+        let elsewhere = RoomId::new("elsewhere");
+        let room = RoomId::default();
+        assert!(transcript
+            .apply_edit(&room, &typo, "mallory", &remote, "rewritten", None)
+            .is_none());
+        assert!(transcript
+            .apply_edit(&room, &typo, "them", &own, "rewritten", None)
+            .is_none());
+        assert!(transcript
+            .apply_edit(&elsewhere, &typo, "them", &remote, "rewritten", None)
+            .is_none());
+        let entry = transcript.recent(1).next().unwrap();
+        assert_eq!(entry.message.body, "teh typo");
+        assert!(!entry.edited);
+
+        let entry = transcript
+            .apply_edit(&room, &typo, "them", &remote, "the typo", None)
+            .unwrap();
+        assert_eq!(entry.message.body, "the typo");
+        assert!(entry.edited);
+
+        assert!(transcript
+            .apply_redaction(&RoomId::new("elsewhere"), &MessageId::new("$typo"))
+            .is_none());
+        let entry = transcript
+            .apply_redaction(&RoomId::default(), &MessageId::new("$typo"))
+            .unwrap();
+        assert!(entry.deleted);
+        assert!(entry.message.body.is_empty());
+
+        let local = Uuid::new_v4();
+        let mut sending = message("not there yet", Utc::now());
+        sending.id = local.into();
+        transcript.push_local(local, sending);
+        assert!(transcript
+            .apply_redaction(&RoomId::default(), &local.into())
+            .is_none());
+
+        assert!(transcript
+            .apply_edit(&room, &MessageId::new("$unknown"), "me", &remote, "x", None)
+            .is_none());
+        assert_eq!(transcript.recent(10).count(), 2);
+    }
+
+    #[test]
+    fn thread_replies_fold_under_their_root() {
+        let mut transcript = Transcript::new(10);
+        let in_thread = |body: &str, root: &str| {
+            let mut reply = message(body, Utc::now());
+            reply.thread = Some(MessageId::new(root));
+            reply
+        };
+
+        let mut root = message("root", Utc::now());
+        root.id = MessageId::new("$root");
+        transcript.push_received(root);
+        transcript.push_received(message("chatter", Utc::now()));
+        transcript.push_received(in_thread("first", "$root"));
+        transcript.push_received(in_thread("orphan", "$gone"));
+        transcript.push_received(in_thread("second", "$root"));
+
+        let lines: Vec<_> = transcript
+            .recent_threaded(10)
+            .into_iter()
+            .map(|entry| entry.message.body.as_str())
+            .collect();
+        assert_eq!(lines, ["root", "first", "second", "chatter", "orphan"]);
+
+        assert_eq!(transcript.thread_len(&MessageId::new("$root")), 2);
+        let root_id = MessageId::new("$root");
+        let thread: Vec<_> = transcript
+            .thread(&root_id)
+            .map(|entry| entry.message.body.as_str())
+            .collect();
+        assert_eq!(thread, ["root", "first", "second"]);
+    }
+
+    #[test]
+    fn reactions_count_each_user_once_and_drop_when_taken_back() {
+        let mut transcript = Transcript::new(10);
+        let mut received = message("ship it", Utc::now());
+        received.id = MessageId::new("$ship");
+        transcript.push_received(received);
+        let id = MessageId::new("$ship");
+
+        transcript.apply_reaction(&id, "bob", "👍", true);
+        transcript.apply_reaction(&id, "carol", "🎉", true);
+        transcript.apply_reaction(&id, "carol", "👍", true);
+        let entry = transcript.apply_reaction(&id, "bob", "👍", true).unwrap();
+        assert_eq!(entry.reaction_summary().unwrap(), "👍 2  🎉 1");
+
+        transcript.apply_reaction(&id, "carol", "🎉", false);
+        let entry = transcript.apply_reaction(&id, "dave", "🎉", false).unwrap();
+        assert_eq!(entry.reaction_summary().unwrap(), "👍 2");
+
+        transcript.apply_reaction(&id, "bob", "👍", false);
+        let entry = transcript
+            .apply_reaction(&id, "carol", "👍", false)
+            .unwrap();
+        assert!(entry.reaction_summary().is_none());
+
+        assert!(transcript
+            .apply_reaction(&MessageId::new("$gone"), "bob", "👍", true)
+            .is_none());
+    }
+
+    #[test]
+    fn read_markers_move_forward_within_their_room() {
+        let mut transcript = Transcript::new(10);
+        let general = RoomId::default();
+        for id in ["$one", "$two"] {
+            let mut received = message(id, Utc::now());
+            received.id = MessageId::new(id);
+            transcript.push_received(received);
+        }
+        let mine = Uuid::new_v4();
+        transcript.push_local(mine, message("mine", Utc::now()));
+
+        transcript.apply_receipt(&general, "bob", &MessageId::new("$one"));
+        let entry = transcript
+            .apply_receipt(&general, "bob", &MessageId::new("$two"))
+            .unwrap();
+        assert_eq!(entry.read_by, ["bob"]);
+
+        let readers: Vec<_> = transcript
+            .recent(10)
+            .map(|entry| entry.read_by.len())
+            .collect();
+        assert_eq!(readers, [0, 1, 0]);
+
+        // unknown messages, and ones from another room, leave the marker alone
+        assert!(transcript
+            .apply_receipt(&general, "bob", &MessageId::new("$gone"))
+            .is_none());
+        assert!(transcript
+            .apply_receipt(&RoomId::new("random"), "bob", &MessageId::new("$one"))
+            .is_none());
+        assert_eq!(transcript.recent(3).nth(1).unwrap().read_by, ["bob"]);
+
+        // our own echo never counts as something to mark read
+        assert_eq!(
+            transcript.last_received(&general).unwrap().message.id,
+            MessageId::new("$two")
+        );
+        assert!(transcript.last_received(&RoomId::new("random")).is_none());
+    }
+
+    #[test]
+    fn oldest_entries_fall_off_past_capacity() {
+        let mut transcript = Transcript::new(2);
+        for body in ["one", "two", "three"] {
+            transcript.push_received(message(body, Utc::now()));
+        }
+
+        let bodies: Vec<_> = transcript
+            .recent(5)
+            .map(|entry| entry.message.body.as_str())
+            .collect();
+        assert_eq!(bodies, ["two", "three"]);
+
+        let last: Vec<_> = transcript
+            .recent(1)
+            .map(|entry| entry.message.body.as_str())
+            .collect();
+        assert_eq!(last, ["three"]);
+    }
+}
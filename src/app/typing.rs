@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+use crate::protocol::{RoomId, TYPING_TIMEOUT};
+
+/// How often "still typing" goes out again while the draft keeps changing; well
+/// inside `TYPING_TIMEOUT`, so the other side never times us out mid-sentence
+const RESEND_AFTER: Duration = Duration::from_secs(TYPING_TIMEOUT.as_secs() / 2);
+
+/// Decides which of our typing notices are worth sending, so a burst of keystrokes
+/// costs one notice rather than one each
+#[derive(Debug, Default)]
+pub struct TypingNotifier {
+    // the room we last told we're typing in, and when
+    typing_in: Option<(RoomId, Instant)>,
+}
+
+impl TypingNotifier {
+    /// the notices to send now that the draft for `room` reads `draft`; commands
+    /// aren't messages, so a draft starting with '/' doesn't count as typing
+    pub fn draft_changed(
+        &mut self,
+        room: &RoomId,
+        draft: &str,
+        now: Instant,
+    ) -> Vec<(RoomId, bool)> {
+        let composing = !draft.trim().is_empty() && !draft.starts_with('/');
+        let mut notices = Vec::new();
+
+        if let Some((typing_room, since)) = &self.typing_in {
+            if !composing || typing_room != room {
+                notices.push((typing_room.clone(), false));
+                self.typing_in = None;
+            } else if now.duration_since(*since) < RESEND_AFTER {
+                return notices;
+            }
+        }
+
+        if composing {
+            notices.push((room.clone(), true));
+            self.typing_in = Some((room.clone(), now));
+        }
+
+        notices
+    }
+}
+
+/// The status line for who's typing, or `None` if nobody is
+pub fn typing_status(users: &[String]) -> Option<String> {
+    let text = match users {
+        [] => return None,
+        [one] => format!("{} is typing…", one),
+        [first, second] => format!("{} and {} are typing…", first, second),
+        [first, second, third] => format!("{}, {} and {} are typing…", first, second, third),
+        _ => "several people are typing…".to_string(),
+    };
+    Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keystrokes_only_repeat_the_notice_after_a_while() {
+        let room = RoomId::new("general");
+        let start = Instant::now();
+        let mut notifier = TypingNotifier::default();
+
+        assert_eq!(
+            notifier.draft_changed(&room, "h", start),
+            [(room.clone(), true)]
+        );
+        assert!(notifier
+            .draft_changed(&room, "he", start + Duration::from_secs(1))
+            .is_empty());
+        assert_eq!(
+            notifier.draft_changed(&room, "hel", start + RESEND_AFTER),
+            [(room.clone(), true)]
+        );
+
+        // clearing the draft, or sending it, stops the notice straight away
+        assert_eq!(
+            notifier.draft_changed(&room, "", start + RESEND_AFTER),
+            [(room.clone(), false)]
+        );
+        assert!(notifier
+            .draft_changed(&room, "", start + RESEND_AFTER)
+            .is_empty());
+    }
+
+    #[test]
+    fn commands_and_room_switches_stop_the_notice() {
+        let general = RoomId::new("general");
+        let random = RoomId::new("random");
+        let now = Instant::now();
+        let mut notifier = TypingNotifier::default();
+
+        assert!(notifier.draft_changed(&general, "/join", now).is_empty());
+
+        notifier.draft_changed(&general, "hi", now);
+        assert_eq!(
+            notifier.draft_changed(&random, "hi there", now),
+            [(general.clone(), false), (random.clone(), true)]
+        );
+        assert_eq!(
+            notifier.draft_changed(&random, "/leave", now),
+            [(random, false)]
+        );
+    }
+
+    #[test]
+    fn status_names_up_to_three_typers() {
+        let users = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(typing_status(&[]), None);
+        assert_eq!(
+            typing_status(&users(&["alice"])).unwrap(),
+            "alice is typing…"
+        );
+        assert_eq!(
+            typing_status(&users(&["alice", "bob"])).unwrap(),
+            "alice and bob are typing…"
+        );
+        assert_eq!(
+            typing_status(&users(&["alice", "bob", "carol", "dave"])).unwrap(),
+            "several people are typing…"
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use ruma_html::{Html, NodeRef};
+
+use crate::protocol::{Block, Inline, RichText, MAX_NESTING};
+
+/// Reads a Matrix `formatted_body` into the styles the terminal can show. Tags we
+/// don't style keep their text; lists and quotes become lines marked `•`, `1.` or
+/// `>`, and the quoted original in old-style replies is dropped as in the plain body.
+/// Elements nested deeper than `MAX_NESTING`, of any kind, are flattened to their
+/// text, so that however deep the sender nests them reading them stays shallow.
+pub fn rich_text(html: &str) -> RichText {
+    let html = Html::parse(html);
+    let nodes = all_nodes(&html);
+    html.sanitize();
+
+    let mut reader = Reader::default();
+    for node in html.children() {
+        reader.block(&node, 0);
+    }
+    reader.end_paragraph();
+
+    drop(html);
+    drop(nodes);
+    RichText {
+        blocks: reader.blocks,
+    }
+}
+
+/// Every node in `html`, each before its children. ruma-html frees a tree by
+/// recursing into it, which a few thousand nested tags overflow the stack with;
+/// dropped after the tree, these go one at a time, parents first, so freeing a
+/// node never takes its children with it.
+fn all_nodes(html: &Html) -> Vec<NodeRef> {
+    let mut nodes = Vec::new();
+    let mut pending: Vec<NodeRef> = html.children().collect();
+
+    while let Some(node) = pending.pop() {
+        pending.extend(node.children());
+        nodes.push(node);
+    }
+    nodes
+}
+
+#[derive(Default)]
+struct Reader {
+    blocks: Vec<Block>,
+    paragraph: Vec<Inline>,
+}
+
+impl Reader {
+    /// `node` as blocks, inside `depth` elements already
+    fn block(&mut self, node: &NodeRef, depth: usize) {
+        let Some(name) = tag(node) else {
+            self.paragraph.extend(inlines(node, depth));
+            return;
+        };
+        if depth >= MAX_NESTING {
+            self.paragraph
+                .push(Inline::Text(collapse_whitespace(&text_of(node))));
+            return;
+        }
+
+        match name.as_str() {
+            "pre" => {
+                self.end_paragraph();
+                let language = node
+                    .children()
+                    .find(|child| tag(child).as_deref() == Some("code"))
+                    .and_then(|code| attribute(&code, "class"))
+                    .and_then(|class| class.strip_prefix("language-").map(str::to_string));
+                let text = text_of(node);
+                self.blocks.push(Block::Code {
+                    language,
+                    text: text.strip_suffix('\n').unwrap_or(&text).to_string(),
+                });
+            }
+            "ul" | "ol" => {
+                let ordered = name == "ol";
+                let items = node
+                    .children()
+                    .filter(|child| tag(child).as_deref() == Some("li"));
+                for (n, item) in items.enumerate() {
+                    self.end_paragraph();
+                    let marker = if ordered {
+                        format!("{}. ", n + 1)
+                    } else {
+                        "• ".to_string()
+                    };
+                    self.paragraph.push(Inline::Text(marker));
+                    // inside both the list and the item
+                    self.children(&item, depth + 2);
+                    self.end_paragraph();
+                }
+            }
+            "blockquote" => {
+                self.end_paragraph();
+                let start = self.blocks.len();
+                self.children(node, depth + 1);
+                self.end_paragraph();
+                for block in &mut self.blocks[start..] {
+                    match block {
+                        Block::Paragraph(inlines) => match inlines.first_mut() {
+                            Some(Inline::Text(text)) => text.insert_str(0, "> "),
+                            _ => inlines.insert(0, Inline::Text("> ".to_string())),
+                        },
+                        Block::Code { .. } => {}
+                    }
+                }
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.end_paragraph();
+                self.paragraph
+                    .push(Inline::Bold(child_inlines(node, depth + 1)));
+                self.end_paragraph();
+            }
+            "p" | "div" | "li" | "table" | "tr" => {
+                self.end_paragraph();
+                self.children(node, depth + 1);
+                self.end_paragraph();
+            }
+            _ => self.paragraph.extend(inlines(node, depth)),
+        }
+    }
+
+    fn children(&mut self, node: &NodeRef, depth: usize) {
+        for child in node.children() {
+            self.block(&child, depth);
+        }
+    }
+
+    /// closes the paragraph so far, unless it's only the whitespace between tags
+    fn end_paragraph(&mut self) {
+        let mut inlines: Vec<Inline> = Vec::new();
+        for inline in std::mem::take(&mut self.paragraph) {
+            match (inlines.last_mut(), inline) {
+                (Some(Inline::Text(text)), Inline::Text(next)) => text.push_str(&next),
+                (_, inline) => inlines.push(inline),
+            }
+        }
+
+        if let Some(Inline::Text(text)) = inlines.first_mut() {
+            *text = text.trim_start().to_string();
+        }
+        if let Some(Inline::Text(text)) = inlines.last_mut() {
+            *text = text.trim_end().to_string();
+        }
+        inlines.retain(|inline| !matches!(inline, Inline::Text(text) if text.is_empty()));
+
+        if !inlines.is_empty() {
+            self.blocks.push(Block::Paragraph(inlines));
+        }
+    }
+}
+
+/// `node` as inlines, inside `depth` elements already
+fn inlines(node: &NodeRef, depth: usize) -> Vec<Inline> {
+    if let Some(text) = node.as_text() {
+        return vec![Inline::Text(collapse_whitespace(&text.borrow()))];
+    }
+
+    let Some(name) = tag(node) else {
+        return Vec::new();
+    };
+    if depth >= MAX_NESTING {
+        return vec![Inline::Text(collapse_whitespace(&text_of(node)))];
+    }
+
+    match name.as_str() {
+        "br" => vec![Inline::LineBreak],
+        "strong" | "b" => vec![Inline::Bold(child_inlines(node, depth + 1))],
+        "em" | "i" => vec![Inline::Italic(child_inlines(node, depth + 1))],
+        "code" => vec![Inline::Code(text_of(node))],
+        "a" => match attribute(node, "href") {
+            Some(url) => vec![Inline::Link {
+                url,
+                text: child_inlines(node, depth + 1),
+            }],
+            None => child_inlines(node, depth + 1),
+        },
+        _ => child_inlines(node, depth + 1),
+    }
+}
+
+/// outside <pre>, a run of whitespace, newlines included, is one space
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut space = false;
+
+    for c in text.chars() {
+        if c.is_whitespace() {
+            space = true;
+            continue;
+        }
+        if std::mem::take(&mut space) {
+            collapsed.push(' ');
+        }
+        collapsed.push(c);
+    }
+
+    if space {
+        collapsed.push(' ');
+    }
+    collapsed
+}
+
+fn child_inlines(node: &NodeRef, depth: usize) -> Vec<Inline> {
+    node.children()
+        .flat_map(|child| inlines(&child, depth))
+        .collect()
+}
+
+fn tag(node: &NodeRef) -> Option<String> {
+    node.as_element()
+        .map(|element| element.name.local.to_string())
+}
+
+fn attribute(node: &NodeRef, name: &str) -> Option<String> {
+    let element = node.as_element()?;
+    let attrs = element.attrs.borrow();
+    attrs
+        .iter()
+        .find(|attr| &*attr.name.local == name)
+        .map(|attr| attr.value.to_string())
+}
+
+/// all the text under `node`, as is; walked with a stack of its own, since
+/// this is what's left to read nodes nested too deep to recurse into
+fn text_of(node: &NodeRef) -> String {
+    let mut text = String::new();
+    let mut pending = vec![node.clone()];
+
+    while let Some(node) = pending.pop() {
+        match node.as_text() {
+            Some(part) => text.push_str(&part.borrow()),
+            None => {
+                let children: Vec<NodeRef> = node.children().collect();
+                pending.extend(children.into_iter().rev());
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Inline {
+        Inline::Text(s.to_string())
+    }
+
+    #[test]
+    fn inline_tags_map_to_styles_and_reply_fallbacks_are_dropped() {
+        let rich = rich_text(
+            "<mx-reply><blockquote>old</blockquote></mx-reply>\
+             <b>hi</b> <em>there</em>, see <a href=\"https://a.org\">this</a><br>and <code>x &lt; y</code>",
+        );
+
+        assert_eq!(
+            rich.blocks,
+            [Block::Paragraph(vec![
+                Inline::Bold(vec![text("hi")]),
+                text(" "),
+                Inline::Italic(vec![text("there")]),
+                text(", see "),
+                Inline::Link {
+                    url: "https://a.org".to_string(),
+                    text: vec![text("this")],
+                },
+                Inline::LineBreak,
+                text("and "),
+                Inline::Code("x < y".to_string()),
+            ])]
+        );
+    }
+
+    #[test]
+    fn blocks_become_paragraphs_and_code() {
+        let rich = rich_text(
+            "<p>steps:</p>\n<ol>\n<li>build</li>\n<li>test</li>\n</ol>\n\
+             <pre><code class=\"language-sh\">cargo test\n</code></pre>\n<blockquote>quoted</blockquote>",
+        );
+
+        assert_eq!(
+            rich.blocks,
+            [
+                Block::Paragraph(vec![text("steps:")]),
+                Block::Paragraph(vec![text("1. build")]),
+                Block::Paragraph(vec![text("2. test")]),
+                Block::Code {
+                    language: Some("sh".to_string()),
+                    text: "cargo test".to_string(),
+                },
+                Block::Paragraph(vec![text("> quoted")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn deep_nesting_flattens_to_text_instead_of_recursing() {
+        let nested = |open: &str, close: &str, depth: usize| {
+            format!("{}deep{}", open.repeat(depth), close.repeat(depth))
+        };
+        let tags = [
+            ("<span>", "</span>"),
+            ("<div>", "</div>"),
+            ("<b>", "</b>"),
+            ("<div><span>", "</span></div>"),
+        ];
+
+        for (open, close) in tags {
+            // past MAX_NESTING the text is still there, without its elements
+            let rich = rich_text(&nested(open, close, 40));
+            let [Block::Paragraph(inlines)] = &rich.blocks[..] else {
+                panic!("expected one paragraph, got {:?}", rich.blocks);
+            };
+            assert!(depth(inlines) <= MAX_NESTING);
+            assert_eq!(text_content(inlines), "deep");
+
+            // several thousand deep still reads on a test thread's stack
+            rich_text(&nested(open, close, 3000));
+        }
+
+        // deeper than ruma-html can free a tree by itself
+        rich_text(&nested("<i>", "", 20_000));
+    }
+
+    fn depth(inlines: &[Inline]) -> usize {
+        inlines
+            .iter()
+            .map(|inline| match inline {
+                Inline::Bold(inner) | Inline::Italic(inner) | Inline::Link { text: inner, .. } => {
+                    1 + depth(inner)
+                }
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn text_content(inlines: &[Inline]) -> String {
+        inlines
+            .iter()
+            .map(|inline| match inline {
+                Inline::Text(text) | Inline::Code(text) => text.clone(),
+                Inline::Bold(inner) | Inline::Italic(inner) | Inline::Link { text: inner, .. } => {
+                    text_content(inner)
+                }
+                Inline::LineBreak => "\n".to_string(),
+            })
+            .collect()
+    }
+}
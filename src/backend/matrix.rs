@@ -1,57 +1,238 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use anyhow::Context;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use matrix_sdk::{
+    attachment::AttachmentConfig,
+    authentication::matrix::MatrixSession,
     config::SyncSettings,
-    room::Room,
+    deserialized_responses::EncryptionInfo,
+    media::{MediaFormat, MediaRequestParameters},
+    notification_settings::RoomNotificationMode,
+    reqwest::{self, Url},
+    room::{
+        edit::EditedContent,
+        reply::{EnforceThread, Reply},
+        IncludeRelations, Receipts, RelationsOptions, Room,
+    },
     ruma::{
-        events::room::message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
-        OwnedRoomId, RoomOrAliasId, ServerName,
+        api::{
+            client::{
+                account::register::{self, RegistrationKind},
+                directory::get_public_rooms_filtered,
+                presence::set_presence,
+                room::{create_room, Visibility},
+                search::search_events::{
+                    self,
+                    v3::{Categories, Criteria, OrderBy},
+                },
+                uiaa::{AuthData, AuthType, Dummy},
+            },
+            error::{ErrorKind, RetryAfter},
+        },
+        directory::Filter,
+        events::{
+            presence::PresenceEvent,
+            reaction::{OriginalSyncReactionEvent, ReactionEventContent},
+            receipt::{ReceiptType, SyncReceiptEvent},
+            relation::{Annotation, RelationType},
+            room::{
+                encryption::RoomEncryptionEventContent,
+                history_visibility::{
+                    HistoryVisibility as MatrixHistoryVisibility, RoomHistoryVisibilityEventContent,
+                },
+                join_rules::{JoinRule as MatrixJoinRule, RoomJoinRulesEventContent},
+                member::{
+                    MembershipChange as MatrixMembershipChange, MembershipState,
+                    OriginalSyncRoomMemberEvent, RoomMemberEventContent, StrippedRoomMemberEvent,
+                },
+                message::{
+                    sanitize::remove_plain_reply_fallback, AddMentions, MessageFormat, MessageType,
+                    OriginalSyncRoomMessageEvent, Relation, ReplyWithinThread,
+                    RoomMessageEventContent, RoomMessageEventContentWithoutRelation,
+                },
+                power_levels::{RoomPowerLevels, UserPowerLevel},
+                redaction::OriginalSyncRoomRedactionEvent,
+                MediaSource,
+            },
+            typing::SyncTypingEvent,
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, InitialStateEvent, StateEventType,
+            SyncMessageLikeEvent,
+        },
+        presence::PresenceState as MatrixPresenceState,
+        push::Action,
+        Int, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedServerName,
+        OwnedTransactionId, OwnedUserId, RoomAliasId, RoomId as MatrixRoomId, RoomOrAliasId,
+        ServerName, TransactionId, UInt, UserId,
     },
-    Client,
+    Client, HttpError, RoomMemberships, RoomState, SessionMeta, SessionTokens,
 };
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
 use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
 use uuid::Uuid;
 
+use crate::backend::html;
+use crate::backend::search_index::{merge_ranked, SearchIndex};
 use crate::{
-    backend::ChatBackend,
-    protocol::{ChatEvent, RoomId},
+    backend::{report, ChatBackend},
+    protocol::{
+        user_label, Attachment, AttachmentKind, Attention, ChatEvent, ChatMessage,
+        ConnectionStatus, DirectoryRoom, HistoryVisibility, JoinRule, MembershipChange, MessageId,
+        MessageKind, MessageOrigin, NewRoom, NotifyLevel, PowerLevel, PowerLevels, Presence,
+        PresenceState, RichText, RoomId, RoomInvite, RoomSetting, RoomSummary, SearchHit,
+        SendStatus, MAX_FORMATTED_LEN,
+    },
 };
 
+/// How `MatrixBackend::login` gets a session on the homeserver
+pub enum Credentials {
+    /// a user ID or localpart and its password
+    Password { user_id: String, password: String },
+    /// the access token of a session that already exists, e.g. one copied out of another client
+    AccessToken(String),
+    /// single sign-on in the browser, which redirects back to a listener on localhost
+    Sso,
+    /// a new account, on a homeserver with open registration
+    Register { username: String, password: String },
+    /// a throwaway guest account, only good for watching rooms; see `ensure_writable`
+    Guest,
+}
+
+/// How to reach the homeserver: where it is and what the connection goes through
+#[derive(Debug, Default)]
+pub struct ConnectOptions {
+    /// plain HTTP straight to the server name, for local test homeservers
+    pub insecure: bool,
+    /// the homeserver's URL, used as-is instead of discovering it through .well-known
+    pub homeserver_url: Option<String>,
+    /// PEM file of CA certificates to trust on top of the system ones
+    pub ca_cert: Option<PathBuf>,
+    /// PEM file with a client certificate to present, and its key unless `client_key` is set
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    /// http://, https:// or socks5:// proxy that all requests go through
+    pub proxy: Option<String>,
+}
+
+/// A text message waiting in a room's send queue, or parked after failing
+struct QueuedMessage {
+    id: Uuid,
+    room: Room,
+    body: String,
+    reply_to: Option<OwnedEventId>,
+    /// root of the thread to post in
+    thread: Option<OwnedEventId>,
+    kind: MessageKind,
+}
+
+/// How many times a message is tried before it's parked for `/retry`
+const MAX_SEND_ATTEMPTS: u32 = 5;
+
+/// Reactions fetched at a time while looking for our own to take back
+const RELATIONS_PAGE_SIZE: u32 = 50;
+
+/// A reaction we've seen, so that its redaction can be shown as it being taken back
+struct KnownReaction {
+    room: RoomId,
+    target: MessageId,
+    sender: OwnedUserId,
+    key: String,
+}
+
+/// A received attachment that can still be downloaded with `/download <id>`
+struct StoredMedia {
+    id: MessageId,
+    name: String,
+    source: MediaSource,
+}
+
+/// How many received attachments stay downloadable before the oldest are forgotten
+const MAX_STORED_MEDIA: usize = 1000;
+
 pub struct MatrixBackend {
     client: Client,
     events_rx: mpsc::Receiver<ChatEvent>,
-    room_map: HashMap<RoomId, OwnedRoomId>,
+    // servers that know about a room, learned while resolving its alias; joining
+    // by bare room id over federation needs at least one of them
+    via_servers: HashMap<OwnedRoomId, Vec<OwnedServerName>>,
+    // filled in by the message handler, oldest first
+    media: Arc<Mutex<VecDeque<StoredMedia>>>,
+    // transaction ids of events this process sent, so their sync echo can be dropped
+    // without also hiding what we send from our other devices
+    sent_txns: Arc<Mutex<HashSet<OwnedTransactionId>>>,
+    events_tx: mpsc::Sender<ChatEvent>,
+    // one queue per room, so a slow or rate-limited room never holds up the
+    // interactive loop or another room, and messages within a room stay in order
+    send_queues: HashMap<OwnedRoomId, mpsc::UnboundedSender<QueuedMessage>>,
+    failed_sends: Arc<Mutex<Vec<QueuedMessage>>>,
+    // the server can't search end-to-end encrypted rooms, so their messages are
+    // indexed here as they arrive
+    search_index: Arc<Mutex<SearchIndex>>,
+    // guest sessions only watch rooms; see `ensure_writable`
+    read_only: bool,
+    // what every sync tells the server about us, which must match what /away said
+    sync_presence: Arc<Mutex<MatrixPresenceState>>,
 }
 
+/// How many results `/search` shows from each of the server and the local index
+const SEARCH_LIMIT: usize = 20;
+
+/// How many messages `/goto` shows on either side of the one it jumps to
+const CONTEXT_SIZE: u32 = 5;
+
 impl MatrixBackend {
     pub async fn login(
         homeserver: &ServerName,
-        user_id: &str,
-        password: &str,
-        insecure: bool,
+        credentials: &Credentials,
+        options: &ConnectOptions,
+        auto_accept_from: Vec<OwnedUserId>,
     ) -> anyhow::Result<Self> {
-        // Insecure mode connects directly to the given host over HTTP rather
-        // than going through .well-known discovery: a local test homeserver's
-        // own well-known response can still claim an https:// base_url (as
-        // Conduit's does), which would silently pull us back to HTTPS even
-        // though we asked to skip TLS.
-        let builder = if insecure {
-            Client::builder().homeserver_url(format!("http://{homeserver}"))
-        } else {
-            Client::builder().server_name(homeserver)
-        };
-        let client = builder.build().await?;
+        let http_client = http_client(options).await?;
+        let client = build_client(homeserver, options, http_client.clone()).await?;
+        let auth = client.matrix_auth();
 
-        client
-            .matrix_auth()
-            .login_username(user_id, password)
-            .initial_device_display_name("rust-chat")
-            .send()
-            .await?;
+        match credentials {
+            Credentials::Password { user_id, password } => {
+                auth.login_username(user_id, password)
+                    .initial_device_display_name("rust-chat")
+                    .send()
+                    .await?;
+            }
+            Credentials::AccessToken(token) => {
+                restore_from_token(&client, &http_client, token).await?
+            }
+            Credentials::Sso => login_sso(&client).await?,
+            Credentials::Register { username, password } => {
+                register_account(&client, username, password).await?
+            }
+            Credentials::Guest => {
+                let mut request = register::v3::Request::new();
+                request.kind = RegistrationKind::Guest;
+                request.initial_device_display_name = Some("rust-chat".to_string());
+
+                auth.register(request)
+                    .await
+                    .with_context(|| format!("{} doesn't allow guest access", homeserver))?;
+            }
+        }
+
+        let read_only = matches!(credentials, Credentials::Guest);
+        Self::start(client, auto_accept_from, read_only).await
+    }
 
+    /// Wires up the event handlers and the sync loop for a client that's just logged in
+    async fn start(
+        client: Client,
+        auto_accept_from: Vec<OwnedUserId>,
+        read_only: bool,
+    ) -> anyhow::Result<Self> {
         let own_user_id = client
             .user_id()
             .context("client has no user_id after login")?
@@ -63,113 +244,2297 @@ impl MatrixBackend {
 
         let (events_tx, events_rx) = mpsc::channel::<ChatEvent>(256);
 
+        let media = Arc::new(Mutex::new(VecDeque::new()));
+        let sent_txns = Arc::new(Mutex::new(HashSet::new()));
+        let search_index = Arc::new(Mutex::new(SearchIndex::default()));
+        // reactions seen this session, by their own event id
+        let reactions = Arc::new(Mutex::new(HashMap::new()));
+
         let handler_user_id = own_user_id.clone();
         let handler_events_tx = events_tx.clone();
-        client.add_event_handler(move |ev: OriginalSyncRoomMessageEvent, room: Room| {
+        let handler_media = media.clone();
+        let handler_sent_txns = sent_txns.clone();
+        let handler_search_index = search_index.clone();
+        client.add_event_handler(
+            move |ev: OriginalSyncRoomMessageEvent,
+                  room: Room,
+                  encryption_info: Option<EncryptionInfo>,
+                  push_actions: Vec<Action>| {
+                let events_tx = handler_events_tx.clone();
+                let own_user_id = handler_user_id.clone();
+                let media = handler_media.clone();
+                let sent_txns = handler_sent_txns.clone();
+                let search_index = handler_search_index.clone();
+                async move {
+                    let encrypted = room.encryption_state().is_encrypted();
+
+                    // the server only hands the transaction id back to the device that sent
+                    // the event, so a known one means this process sent it and the session
+                    // already shows a local echo; confirm it with the server timestamp instead
+                    if let Some(txn_id) = &ev.unsigned.transaction_id {
+                        if sent_txns.lock().unwrap().remove(txn_id) {
+                            // attachment sends use random transaction ids and have no echo
+                            if let Ok(id) = Uuid::parse_str(txn_id.as_str()) {
+                                let status = SendStatus::Sent {
+                                    remote_id: MessageId::new(ev.event_id.to_string()),
+                                    ts: Some(to_utc(ev.origin_server_ts)),
+                                };
+                                let _ = events_tx
+                                    .send(ChatEvent::SendStatus {
+                                        id,
+                                        room: RoomId::new(room.room_id().to_string()),
+                                        status,
+                                    })
+                                    .await;
+                            }
+
+                            // our own messages need indexing too, and this is where they
+                            // first show up with their event id
+                            if encrypted {
+                                let from = sender_label(&room, &ev.sender).await;
+                                let echo =
+                                    message_to_chat_event(ev, &room, from, MessageOrigin::Local);
+                                if let ChatEvent::Message(message) = echo {
+                                    search_index.lock().unwrap().insert(message);
+                                }
+                            }
+                            return;
+                        }
+                    }
+
+                    // edits are separate events pointing at the original, so the session
+                    // can update the message it already has rather than show a new one.
+                    // Only the original's sender can edit it, and only in its own room,
+                    // so an edit whose original can't be found in this room is dropped.
+                    if let Some(Relation::Replacement(replacement)) = &ev.content.relates_to {
+                        let original_sender =
+                            match room.load_or_fetch_event(&replacement.event_id, None).await {
+                                Ok(original) => original.raw().get_field::<OwnedUserId>("sender"),
+                                Err(_) => return,
+                            };
+                        if !matches!(original_sender, Ok(Some(sender)) if sender == ev.sender) {
+                            return;
+                        }
+
+                        let id = MessageId::new(replacement.event_id.to_string());
+                        let new_content = &replacement.new_content.msgtype;
+                        let body = new_content.body();
+                        search_index.lock().unwrap().edit(&id, body);
+
+                        let edited = ChatEvent::Edited {
+                            ts: to_utc(ev.origin_server_ts),
+                            room: RoomId::new(room.room_id().to_string()),
+                            id,
+                            from: sender_label(&room, &ev.sender).await,
+                            origin: if ev.sender == own_user_id {
+                                MessageOrigin::OwnDevice { device: None }
+                            } else {
+                                MessageOrigin::Remote { device: None }
+                            },
+                            body: body.to_string(),
+                            formatted: formatted(new_content),
+                        };
+                        let _ = events_tx.send(edited).await;
+                        return;
+                    }
+
+                    // the sending device is only known for encrypted events
+                    let device = encryption_info
+                        .and_then(|info| info.sender_device)
+                        .map(|device| device.to_string());
+
+                    let origin = if ev.sender == own_user_id {
+                        MessageOrigin::OwnDevice { device }
+                    } else {
+                        MessageOrigin::Remote { device }
+                    };
+
+                    let source = media_source(&ev.content.msgtype);
+                    let from = sender_label(&room, &ev.sender).await;
+                    let mut event = message_to_chat_event(ev, &room, from, origin);
+
+                    // the server's push rules already account for the room's notification
+                    // level and for mentions of our user id or display name
+                    if let ChatEvent::Message(message) = &mut event {
+                        if matches!(message.origin, MessageOrigin::Remote { .. }) {
+                            message.attention = push_attention(&push_actions);
+                        }
+                    }
+
+                    if let (
+                        Some(source),
+                        ChatEvent::Message(ChatMessage {
+                            id,
+                            attachment: Some(attachment),
+                            ..
+                        }),
+                    ) = (source, &event)
+                    {
+                        let stored = StoredMedia {
+                            id: id.clone(),
+                            name: attachment.name.clone(),
+                            source,
+                        };
+                        let mut media = media.lock().unwrap();
+                        if media.len() == MAX_STORED_MEDIA {
+                            media.pop_front();
+                        }
+                        media.push_back(stored);
+                    }
+
+                    if let (true, ChatEvent::Message(message)) = (encrypted, &event) {
+                        search_index.lock().unwrap().insert(message.clone());
+                    }
+
+                    let _ = events_tx.send(event).await;
+                }
+            },
+        );
+
+        // our own reactions come back through here too, which is how they get shown
+        let handler_events_tx = events_tx.clone();
+        let handler_reactions = reactions.clone();
+        client.add_event_handler(move |ev: OriginalSyncReactionEvent, room: Room| {
             let events_tx = handler_events_tx.clone();
-            let own_user_id = handler_user_id.clone();
+            let reactions = handler_reactions.clone();
             async move {
-                // we sent this message ourselves; app.rs doesn't expect an echo of its own sends
-                if ev.sender == own_user_id {
+                let annotation = &ev.content.relates_to;
+                let room_id = RoomId::new(room.room_id().to_string());
+                let target = MessageId::new(annotation.event_id.to_string());
+
+                reactions.lock().unwrap().insert(
+                    ev.event_id.clone(),
+                    KnownReaction {
+                        room: room_id.clone(),
+                        target: target.clone(),
+                        sender: ev.sender.clone(),
+                        key: annotation.key.clone(),
+                    },
+                );
+
+                let event = ChatEvent::Reaction {
+                    room: room_id,
+                    id: target,
+                    user: sender_label(&room, &ev.sender).await,
+                    key: annotation.key.clone(),
+                    added: true,
+                };
+                let _ = events_tx.send(event).await;
+            }
+        });
+
+        let handler_events_tx = events_tx.clone();
+        let handler_search_index = search_index.clone();
+        let handler_reactions = reactions.clone();
+        client.add_event_handler(move |ev: OriginalSyncRoomRedactionEvent, room: Room| {
+            let events_tx = handler_events_tx.clone();
+            let search_index = handler_search_index.clone();
+            let reactions = handler_reactions.clone();
+            async move {
+                // room v11 moved `redacts` into the content; older rooms have it at the top level
+                let Some(redacted) = ev.content.redacts.as_ref().or(ev.redacts.as_ref()) else {
                     return;
-                }
+                };
 
-                let MessageType::Text(text) = ev.content.msgtype else {
+                // reactions are taken back by redacting them
+                let reaction = reactions.lock().unwrap().remove(redacted);
+                if let Some(reaction) = reaction {
+                    let event = ChatEvent::Reaction {
+                        room: reaction.room,
+                        id: reaction.target,
+                        user: sender_label(&room, &reaction.sender).await,
+                        key: reaction.key,
+                        added: false,
+                    };
+                    let _ = events_tx.send(event).await;
+                    return;
+                }
+                // and ones from before this session aren't messages being deleted either
+                let kind = match room.load_or_fetch_event(redacted, None).await {
+                    Ok(event) => event.kind.event_type(),
+                    Err(_) => None,
+                };
+                if kind.as_deref() == Some("m.reaction") {
                     return;
+                }
+
+                let id = MessageId::new(redacted.to_string());
+                search_index.lock().unwrap().remove(&id);
+
+                let event = ChatEvent::Redacted {
+                    ts: to_utc(ev.origin_server_ts),
+                    room: RoomId::new(room.room_id().to_string()),
+                    id,
+                    by: sender_label(&room, &ev.sender).await,
+                    reason: ev.content.reason.clone(),
                 };
+                let _ = events_tx.send(event).await;
+            }
+        });
+
+        let handler_events_tx = events_tx.clone();
+        client.add_event_handler(move |ev: OriginalSyncRoomMemberEvent, room: Room| {
+            let events_tx = handler_events_tx.clone();
+            async move {
+                let by = sender_label(&room, &ev.sender).await;
+                if let Some(event) = member_to_chat_event(&ev, &room, by) {
+                    let _ = events_tx.send(event).await;
+                }
+            }
+        });
+
+        // m.typing always carries everyone typing in the room, and the server times
+        // each of them out, so there's nothing to track between events
+        let handler_user_id = own_user_id.clone();
+        let handler_events_tx = events_tx.clone();
+        client.add_event_handler(move |ev: SyncTypingEvent, room: Room| {
+            let events_tx = handler_events_tx.clone();
+            let own_user_id = handler_user_id.clone();
+            async move {
+                let mut users = Vec::new();
+                for user_id in ev.content.user_ids.iter().filter(|id| **id != own_user_id) {
+                    users.push(sender_label(&room, user_id).await);
+                }
 
-                let ts = ev
-                    .origin_server_ts
-                    .to_system_time()
-                    .map(DateTime::<Utc>::from)
-                    .unwrap_or_else(Utc::now);
-
-                let event = ChatEvent::Message {
-                    // matrix event ids are opaque strings, not UUIDs, so mint a local one
-                    id: Uuid::new_v4(),
-                    ts,
-                    from: ev.sender.to_string(),
+                let event = ChatEvent::Typing {
                     room: RoomId::new(room.room_id().to_string()),
-                    body: text.body,
+                    users,
                 };
+                let _ = events_tx.send(event).await;
+            }
+        });
+
+        // private receipts are only ever our own, so only public ones say who read what
+        let handler_user_id = own_user_id.clone();
+        let handler_events_tx = events_tx.clone();
+        client.add_event_handler(move |ev: SyncReceiptEvent, room: Room| {
+            let events_tx = handler_events_tx.clone();
+            let own_user_id = handler_user_id.clone();
+            async move {
+                for (event_id, receipts) in &ev.content.0 {
+                    let Some(readers) = receipts.get(&ReceiptType::Read) else {
+                        continue;
+                    };
+
+                    for user_id in readers.keys().filter(|id| **id != own_user_id) {
+                        let event = ChatEvent::Receipt {
+                            room: RoomId::new(room.room_id().to_string()),
+                            user: sender_label(&room, user_id).await,
+                            id: MessageId::new(event_id.to_string()),
+                        };
+                        let _ = events_tx.send(event).await;
+                    }
+                }
+            }
+        });
 
+        let handler_user_id = own_user_id.clone();
+        let handler_events_tx = events_tx.clone();
+        // a room each user was last found in, so naming them needn't search every room
+        let shared_rooms = Arc::new(Mutex::new(HashMap::new()));
+        client.add_event_handler(move |ev: PresenceEvent, client: Client| {
+            let events_tx = handler_events_tx.clone();
+            let own_user_id = handler_user_id.clone();
+            let shared_rooms = shared_rooms.clone();
+            async move {
+                if ev.sender == own_user_id {
+                    return;
+                }
+
+                let state = match ev.content.presence {
+                    MatrixPresenceState::Online => PresenceState::Online,
+                    MatrixPresenceState::Unavailable => PresenceState::Away,
+                    _ => PresenceState::Offline,
+                };
+                let event = ChatEvent::Presence {
+                    user: presence_label(&client, &shared_rooms, &ev.sender).await,
+                    presence: Presence {
+                        state,
+                        message: ev.content.status_msg.clone(),
+                    },
+                };
                 let _ = events_tx.send(event).await;
             }
         });
 
-        let sync_client = client.clone();
-        tokio::spawn(async move {
-            let result = sync_client.sync(SyncSettings::new()).await;
-            let text = match result {
-                Ok(()) => "matrix sync loop ended".to_string(),
-                Err(e) => format!("matrix sync loop ended: {}", e),
-            };
-            let _ = events_tx.send(ChatEvent::System(text)).await;
+        let handler_user_id = own_user_id.clone();
+        let handler_events_tx = events_tx.clone();
+        let auto_accept_from = Arc::new(auto_accept_from);
+        client.add_event_handler(move |ev: StrippedRoomMemberEvent, room: Room| {
+            let events_tx = handler_events_tx.clone();
+            let own_user_id = handler_user_id.clone();
+            let auto_accept_from = auto_accept_from.clone();
+            async move {
+                if ev.state_key != own_user_id || ev.content.membership != MembershipState::Invite {
+                    return;
+                }
+
+                let invite = RoomInvite {
+                    room: RoomId::new(room.room_id().to_string()),
+                    name: room.name(),
+                    inviter: Some(ev.sender.to_string()),
+                };
+
+                if !auto_accept_from.contains(&ev.sender) {
+                    let _ = events_tx.send(ChatEvent::Invite(invite)).await;
+                    return;
+                }
+
+                // the join retries with backoff, which would hold up the rest of the sync
+                tokio::spawn(async move {
+                    let text = match join_invited_room(&room).await {
+                        Ok(()) => format!("auto-accepted invite to {}", invite),
+                        Err(e) => format!("failed to auto-accept invite to {}: {}", invite, e),
+                    };
+                    let _ = events_tx.send(ChatEvent::System(text)).await;
+                });
+            }
         });
 
+        let _ = events_tx
+            .send(ChatEvent::Connection(ConnectionStatus::Connected))
+            .await;
+
+        let sync_presence = Arc::new(Mutex::new(MatrixPresenceState::Online));
+        tokio::spawn(supervise_sync(
+            client.clone(),
+            events_tx.clone(),
+            sync_presence.clone(),
+        ));
+
         Ok(Self {
             client,
             events_rx,
-            room_map: HashMap::new(),
+            via_servers: HashMap::new(),
+            media,
+            sent_txns,
+            events_tx,
+            send_queues: HashMap::new(),
+            failed_sends: Arc::new(Mutex::new(Vec::new())),
+            search_index,
+            read_only,
+            sync_presence,
         })
     }
+
+    /// Guests can join rooms that let them in, but we keep them to watching
+    fn ensure_writable(&self) -> anyhow::Result<()> {
+        if self.read_only {
+            anyhow::bail!("this is a read-only guest session; log in with an account to send");
+        }
+        Ok(())
+    }
+
+    /// Finds a room the client already knows about, whichever way the user named it:
+    /// room id, canonical or alternative alias, or (if unambiguous) display name
+    fn known_room(&self, room: &RoomId) -> Option<Room> {
+        let wanted = room.as_str();
+
+        if let Ok(room_id) = <&MatrixRoomId>::try_from(wanted) {
+            return self.client.get_room(room_id);
+        }
+
+        let rooms = self.client.rooms();
+
+        if let Some(found) = rooms.iter().find(|r| {
+            r.canonical_alias().is_some_and(|a| a.as_str() == wanted)
+                || r.alt_aliases().iter().any(|a| a.as_str() == wanted)
+        }) {
+            return Some(found.clone());
+        }
+
+        let mut named = rooms.into_iter().filter(|r| {
+            r.cached_display_name()
+                .is_some_and(|name| name.to_string() == wanted)
+        });
+
+        match (named.next(), named.next()) {
+            (Some(found), None) => Some(found),
+            _ => None,
+        }
+    }
+
+    fn invited_room(&self, room: &RoomId) -> anyhow::Result<Room> {
+        self.known_room(room)
+            .filter(|r| r.state() == RoomState::Invited)
+            .with_context(|| format!("no pending invite for '{}'", room))
+    }
+
+    /// A transaction id for an event we're about to send, remembered so the
+    /// handler can recognise the event when it comes back down the sync
+    fn track_send(&self, txn_id: OwnedTransactionId) -> OwnedTransactionId {
+        self.sent_txns.lock().unwrap().insert(txn_id.clone());
+        txn_id
+    }
+
+    /// Hands a message to its room's queue, starting the queue on first use
+    fn enqueue(&mut self, message: QueuedMessage) {
+        let status = ChatEvent::SendStatus {
+            id: message.id,
+            room: RoomId::new(message.room.room_id().to_string()),
+            status: SendStatus::Pending,
+        };
+        report(&self.events_tx, status);
+
+        self.track_send(message.id.to_string().into());
+
+        let queue = self
+            .send_queues
+            .entry(message.room.room_id().to_owned())
+            .or_insert_with(|| {
+                let (queue_tx, queue_rx) = mpsc::unbounded_channel();
+                tokio::spawn(run_send_queue(
+                    queue_rx,
+                    self.events_tx.clone(),
+                    self.failed_sends.clone(),
+                    self.sent_txns.clone(),
+                ));
+                queue_tx
+            });
+
+        // the worker only stops once we drop its sender, so this can't fail
+        let _ = queue.send(message);
+    }
+
+    fn joined_room(&self, room: &RoomId) -> anyhow::Result<Room> {
+        self.known_room(room)
+            .filter(|r| r.state() == RoomState::Joined)
+            .with_context(|| format!("not currently in room '{}', join it first", room))
+    }
 }
 
-#[async_trait]
-impl ChatBackend for MatrixBackend {
-    async fn poll_events(&mut self) -> anyhow::Result<Vec<ChatEvent>> {
-        let mut events = Vec::new();
+async fn build_client(
+    homeserver: &ServerName,
+    options: &ConnectOptions,
+    http_client: reqwest::Client,
+) -> anyhow::Result<Client> {
+    let builder = Client::builder().http_client(http_client);
 
-        while let Ok(ev) = self.events_rx.try_recv() {
-            events.push(ev);
+    // Insecure mode connects directly to the given host over HTTP rather
+    // than going through .well-known discovery: a local test homeserver's
+    // own well-known response can still claim an https:// base_url (as
+    // Conduit's does), which would silently pull us back to HTTPS even
+    // though we asked to skip TLS.
+    let builder = match &options.homeserver_url {
+        Some(url) => builder.homeserver_url(url),
+        None if options.insecure => builder.homeserver_url(format!("http://{homeserver}")),
+        None => builder.server_name(homeserver),
+    };
+
+    Ok(builder.build().await?)
+}
+
+/// The HTTP client every request goes through, including the ones made before we
+/// have a session. Built here rather than by matrix-sdk because its builder has no
+/// way to present a client certificate.
+async fn http_client(options: &ConnectOptions) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent("rust-chat")
+        .tls_version_min(reqwest::tls::Version::TLS_1_2);
+
+    if let Some(path) = &options.ca_cert {
+        let pem = read_pem(path).await?;
+        let certs = reqwest::Certificate::from_pem_bundle(&pem)
+            .ok()
+            .filter(|certs| !certs.is_empty())
+            .with_context(|| format!("no CA certificates in {}", path.display()))?;
+        builder = builder.tls_certs_merge(certs);
+    }
+
+    if let Some(path) = &options.client_cert {
+        // reqwest wants the certificate chain and its key in one PEM buffer
+        let mut pem = read_pem(path).await?;
+        if let Some(key) = &options.client_key {
+            pem.push(b'\n');
+            pem.extend(read_pem(key).await?);
         }
+        let identity = reqwest::Identity::from_pem(&pem)
+            .with_context(|| format!("no client certificate and key in {}", path.display()))?;
+        builder = builder.identity(identity);
+    }
 
-        Ok(events)
+    if let Some(proxy) = &options.proxy {
+        let proxy = reqwest::Proxy::all(proxy)
+            .with_context(|| format!("'{}' is not a proxy URL", proxy))?;
+        builder = builder.proxy(proxy);
     }
 
-    async fn join_room(&mut self, room: &RoomId) -> anyhow::Result<()> {
-        let room_or_alias = RoomOrAliasId::parse(room.as_str())
-            .with_context(|| format!("'{}' is not a valid room id or alias", room))?;
+    Ok(builder.build()?)
+}
 
-        let joined = self
-            .client
-            .join_room_by_id_or_alias(&room_or_alias, &[])
-            .await
-            .with_context(|| format!("failed to join '{}'", room))?;
+async fn read_pem(path: &Path) -> anyhow::Result<Vec<u8>> {
+    tokio::fs::read(path)
+        .await
+        .with_context(|| format!("couldn't read {}", path.display()))
+}
 
-        self.room_map
-            .insert(room.clone(), joined.room_id().to_owned());
+/// Logs in with an existing access token. A session also needs the user and device
+/// the token belongs to, which only the homeserver can tell us. Our crypto store is
+/// new and in memory, so the device's keys are made afresh: a token borrowed from a
+/// client still using that device leaves the two with conflicting keys.
+async fn restore_from_token(
+    client: &Client,
+    http_client: &reqwest::Client,
+    token: &str,
+) -> anyhow::Result<()> {
+    #[derive(Deserialize)]
+    struct WhoAmI {
+        user_id: OwnedUserId,
+        device_id: Option<OwnedDeviceId>,
+    }
 
-        Ok(())
+    // the client can't make authenticated requests until it has a session, so ask directly
+    let url = client
+        .homeserver()
+        .join("_matrix/client/v3/account/whoami")?;
+    let response = http_client
+        .get(url)
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()
+        .context("the homeserver didn't accept the access token")?;
+    let whoami: WhoAmI = serde_json::from_slice(&response.bytes().await?)?;
+
+    let device_id = whoami
+        .device_id
+        .context("the access token isn't tied to a device, so it can't be used here")?;
+
+    let session = MatrixSession {
+        meta: SessionMeta {
+            user_id: whoami.user_id,
+            device_id,
+        },
+        tokens: SessionTokens {
+            access_token: token.to_string(),
+            refresh_token: None,
+        },
+    };
+    client.restore_session(session).await?;
+
+    Ok(())
+}
+
+/// Single sign-on: the user logs in with the identity provider in their browser, which
+/// then redirects to a one-shot HTTP listener here with a login token we exchange for
+/// a session
+async fn login_sso(client: &Client) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let redirect_url = format!("http://{}/", listener.local_addr()?);
+
+    let login_url = client
+        .matrix_auth()
+        .get_sso_login_url(&redirect_url, None)
+        .await
+        .context("the homeserver doesn't support single sign-on")?;
+    println!("Open this link in a browser to log in:\n  {}", login_url);
+
+    let token = loop {
+        let (mut stream, _) = listener.accept().await?;
+
+        let mut request_line = String::new();
+        BufReader::new(&mut stream)
+            .read_line(&mut request_line)
+            .await?;
+
+        // browsers also ask for things like /favicon.ico, which get a 404
+        let Some(token) = sso_login_token(&request_line) else {
+            let _ = stream
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .await;
+            continue;
+        };
+
+        let body = "Logged in to rust-chat, you can close this tab.";
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+        break token;
+    };
+
+    client
+        .matrix_auth()
+        .login_token(&token)
+        .initial_device_display_name("rust-chat")
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+/// The `loginToken` from the request line of the homeserver's SSO redirect,
+/// e.g. `GET /?loginToken=abc HTTP/1.1`
+fn sso_login_token(request_line: &str) -> Option<String> {
+    let path = request_line.split_whitespace().nth(1)?;
+    let url = Url::parse("http://localhost").ok()?.join(path).ok()?;
+
+    url.query_pairs()
+        .find(|(key, _)| key == "loginToken")
+        .map(|(_, token)| token.into_owned())
+}
+
+/// Registers an account the way open-registration homeservers expect: the first
+/// request is answered with the auth steps it needs, and the only one we can do
+/// ourselves is the no-op `m.login.dummy`
+async fn register_account(client: &Client, username: &str, password: &str) -> anyhow::Result<()> {
+    let request = |auth| {
+        let mut request = register::v3::Request::new();
+        request.username = Some(username.to_string());
+        request.password = Some(password.to_string());
+        request.initial_device_display_name = Some("rust-chat".to_string());
+        request.auth = auth;
+        request
+    };
+
+    let error = match client.matrix_auth().register(request(None)).await {
+        Ok(_) => return Ok(()),
+        Err(error) => error,
+    };
+    let Some(info) = error.as_uiaa_response() else {
+        return Err(error).context("registration failed");
+    };
+
+    let open = info
+        .flows
+        .iter()
+        .any(|flow| flow.stages.iter().all(|stage| *stage == AuthType::Dummy));
+    if !open {
+        anyhow::bail!(
+            "registration on this homeserver needs more than a username and password \
+             (e.g. a captcha or an email address); register in another client instead"
+        );
     }
 
-    async fn leave_room(&mut self, room: &RoomId) -> anyhow::Result<()> {
-        let Some(room_id) = self.room_map.remove(room) else {
-            anyhow::bail!("not currently in room '{}'", room);
+    let mut dummy = Dummy::new();
+    dummy.session = info.session.clone();
+
+    client
+        .matrix_auth()
+        .register(request(Some(AuthData::Dummy(dummy))))
+        .await
+        .context("registration failed")?;
+
+    Ok(())
+}
+
+/// Works through one room's queue in order. Each message is retried in place
+/// (honouring the server's `retry_after` on rate limits) before the next one is
+/// sent, and parked in `failed` for `/retry` when it can't be delivered.
+async fn run_send_queue(
+    mut queue_rx: mpsc::UnboundedReceiver<QueuedMessage>,
+    events_tx: mpsc::Sender<ChatEvent>,
+    failed: Arc<Mutex<Vec<QueuedMessage>>>,
+    sent_txns: Arc<Mutex<HashSet<OwnedTransactionId>>>,
+) {
+    while let Some(message) = queue_rx.recv().await {
+        let sent = match message_content(&message).await {
+            Ok(content) => send_with_retries(&message, content)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        };
+
+        let status = match sent {
+            // the server timestamp follows once the event comes back through sync
+            Ok(event_id) => SendStatus::Sent {
+                remote_id: MessageId::new(event_id.to_string()),
+                ts: None,
+            },
+            Err(e) => SendStatus::Failed {
+                error: format!("{:#}", e),
+            },
         };
 
-        if let Some(matrix_room) = self.client.get_room(&room_id) {
-            matrix_room.leave().await?;
+        let event = ChatEvent::SendStatus {
+            id: message.id,
+            room: RoomId::new(message.room.room_id().to_string()),
+            status: status.clone(),
+        };
+
+        if matches!(status, SendStatus::Failed { .. }) {
+            // nothing is coming back for it; `/retry` tracks it afresh
+            let txn_id: OwnedTransactionId = message.id.to_string().into();
+            sent_txns.lock().unwrap().remove(&txn_id);
+            failed.lock().unwrap().push(message);
         }
 
-        Ok(())
+        let _ = events_tx.send(event).await;
+    }
+}
+
+/// The content for a text, notice or emote, with an HTML `formatted_body` as well if
+/// the body uses Markdown
+fn body_content(kind: MessageKind, body: &str) -> RoomMessageEventContentWithoutRelation {
+    type Content = RoomMessageEventContentWithoutRelation;
+
+    match (
+        kind,
+        RichText::from_markdown(body).map(|rich| rich.to_html()),
+    ) {
+        (MessageKind::Text, None) => Content::text_plain(body),
+        (MessageKind::Text, Some(html)) => Content::text_html(body, html),
+        (MessageKind::Notice, None) => Content::notice_plain(body),
+        (MessageKind::Notice, Some(html)) => Content::notice_html(body, html),
+        (MessageKind::Emote, None) => Content::emote_plain(body),
+        (MessageKind::Emote, Some(html)) => Content::emote_html(body, html),
     }
+}
 
-    async fn send_message(&mut self, room: &RoomId, body: &str) -> anyhow::Result<()> {
-        let room_id = self
-            .room_map
-            .get(room)
-            .with_context(|| format!("not currently in room '{}', join it first", room))?;
+/// The styled version of a text, notice or emote, if it was sent with one we can read
+/// and it isn't too long to be worth parsing
+fn formatted(msgtype: &MessageType) -> Option<RichText> {
+    let formatted = match msgtype {
+        MessageType::Text(c) => c.formatted.as_ref(),
+        MessageType::Notice(c) => c.formatted.as_ref(),
+        MessageType::Emote(c) => c.formatted.as_ref(),
+        _ => None,
+    }?;
 
-        let matrix_room = self
-            .client
-            .get_room(room_id)
-            .context("joined room is no longer known to the client")?;
+    let readable = matches!(formatted.format, MessageFormat::Html)
+        && formatted.body.len() <= MAX_FORMATTED_LEN;
+    readable.then(|| html::rich_text(&formatted.body))
+}
 
-        matrix_room
-            .send(RoomMessageEventContent::text_plain(body))
-            .await?;
+/// The event to send for a queued message; replies and thread messages quote the
+/// message they follow, which may mean fetching it from the server
+async fn message_content(message: &QueuedMessage) -> anyhow::Result<RoomMessageEventContent> {
+    let content = body_content(message.kind, &message.body);
 
-        Ok(())
+    let (event_id, enforce_thread, add_mentions) = match (&message.reply_to, &message.thread) {
+        (None, None) => return Ok(content.into()),
+        (Some(reply_to), None) => (reply_to, EnforceThread::MaybeThreaded, AddMentions::Yes),
+        (Some(reply_to), Some(_)) => (
+            reply_to,
+            EnforceThread::Threaded(ReplyWithinThread::Yes),
+            AddMentions::Yes,
+        ),
+        // clients without thread support see a plain thread message as a reply to the root
+        (None, Some(root)) => (
+            root,
+            EnforceThread::Threaded(ReplyWithinThread::No),
+            AddMentions::No,
+        ),
+    };
+
+    let reply = Reply {
+        event_id: event_id.clone(),
+        enforce_thread,
+        add_mentions,
+    };
+
+    message
+        .room
+        .make_reply_event(content, reply)
+        .await
+        .with_context(|| format!("can't reply to {}", event_id))
+}
+
+async fn send_with_retries(
+    message: &QueuedMessage,
+    content: RoomMessageEventContent,
+) -> matrix_sdk::Result<OwnedEventId> {
+    let mut attempt = 0;
+
+    loop {
+        // reusing the transaction id makes a retry of a send that actually
+        // reached the server a no-op rather than a duplicate
+        let result = message
+            .room
+            .send(content.clone())
+            .with_transaction_id(message.id.to_string().into())
+            .await;
+
+        let error = match result {
+            Ok(sent) => return Ok(sent.response.event_id),
+            Err(e) => e,
+        };
+
+        attempt += 1;
+        if attempt >= MAX_SEND_ATTEMPTS || !is_transient(&error) {
+            return Err(error);
+        }
+
+        let retry_in = server_retry_after(&error).unwrap_or_else(|| sync_backoff(attempt));
+        sleep(retry_in).await;
+    }
+}
+
+/// Whether the same request might succeed later: network failures, rate limits
+/// and server-side errors, as opposed to e.g. missing permissions
+fn is_transient(error: &matrix_sdk::Error) -> bool {
+    let matrix_sdk::Error::Http(http) = error else {
+        return false;
+    };
+
+    if matches!(http.as_ref(), HttpError::Reqwest(_)) {
+        return true;
+    }
+
+    match http.as_client_api_error() {
+        Some(api_error) => {
+            api_error.status_code.is_server_error()
+                || matches!(
+                    error.client_api_error_kind(),
+                    Some(ErrorKind::LimitExceeded(_))
+                )
+        }
+        None => false,
+    }
+}
+
+/// Longest wait between two sync attempts while the homeserver is unreachable
+const MAX_SYNC_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Keeps syncing for as long as the backend lives. Network failures and server
+/// errors are retried with exponential backoff (or the server's own
+/// `retry_after`), while errors that no retry can fix end the loop.
+async fn supervise_sync(
+    client: Client,
+    events_tx: mpsc::Sender<ChatEvent>,
+    presence: Arc<Mutex<MatrixPresenceState>>,
+) {
+    let settings = SyncSettings::new().timeout(Duration::from_secs(30));
+    let mut failures = 0u32;
+
+    // the backend was dropped, nobody is listening anymore
+    while !events_tx.is_closed() {
+        // syncing as online would mark us back from /away
+        let presence = presence.lock().unwrap().clone();
+        let error = match client
+            .sync_once(settings.clone().set_presence(presence))
+            .await
+        {
+            Ok(_) => {
+                if failures > 0 {
+                    failures = 0;
+                    let _ = events_tx
+                        .send(ChatEvent::Connection(ConnectionStatus::Connected))
+                        .await;
+                }
+                continue;
+            }
+            Err(e) => e,
+        };
+
+        if let Some(reason) = fatal_sync_error(&error) {
+            let status = ConnectionStatus::Disconnected {
+                reason: reason.to_string(),
+            };
+            let _ = events_tx.send(ChatEvent::Connection(status)).await;
+            return;
+        }
+
+        failures += 1;
+        let retry_in = server_retry_after(&error).unwrap_or_else(|| sync_backoff(failures));
+
+        let status = ConnectionStatus::Reconnecting {
+            error: error.to_string(),
+            attempt: failures,
+            retry_in,
+        };
+        let _ = events_tx.send(ChatEvent::Connection(status)).await;
+
+        sleep(retry_in).await;
+    }
+}
+
+/// 1s, 2s, 4s, ... capped at [`MAX_SYNC_BACKOFF`]
+fn sync_backoff(failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16);
+    Duration::from_secs(1u64 << exponent).min(MAX_SYNC_BACKOFF)
+}
+
+/// Errors that mean our session is gone for good, so syncing again can't help
+fn fatal_sync_error(error: &matrix_sdk::Error) -> Option<&'static str> {
+    match error.client_api_error_kind()? {
+        ErrorKind::UnknownToken(_) | ErrorKind::MissingToken => {
+            Some("the access token is no longer valid, log in again")
+        }
+        ErrorKind::UserDeactivated => Some("this account has been deactivated"),
+        ErrorKind::UserLocked => Some("this account has been locked"),
+        _ => None,
+    }
+}
+
+fn server_retry_after(error: &matrix_sdk::Error) -> Option<Duration> {
+    match error.client_api_error_kind()? {
+        ErrorKind::LimitExceeded(data) => match data.retry_after.as_ref()? {
+            RetryAfter::Delay(delay) => Some(*delay),
+            RetryAfter::DateTime(at) => at.duration_since(SystemTime::now()).ok(),
+        },
+        _ => None,
+    }
+}
+
+/// Joins a room we were just invited to. Servers can reject a join that arrives
+/// right after the invite (the invite hasn't federated yet), so retry a few times.
+async fn join_invited_room(room: &Room) -> matrix_sdk::Result<()> {
+    let mut delay = Duration::from_secs(1);
+
+    loop {
+        match room.join().await {
+            Ok(()) => return Ok(()),
+            Err(e) if delay > Duration::from_secs(8) => return Err(e),
+            Err(_) => {
+                sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+}
+
+fn to_utc(ts: MilliSecondsSinceUnixEpoch) -> DateTime<Utc> {
+    ts.to_system_time()
+        .map(DateTime::<Utc>::from)
+        .unwrap_or_else(Utc::now)
+}
+
+fn media_source(msgtype: &MessageType) -> Option<MediaSource> {
+    match msgtype {
+        MessageType::Image(c) => Some(c.source.clone()),
+        MessageType::Audio(c) => Some(c.source.clone()),
+        MessageType::Video(c) => Some(c.source.clone()),
+        MessageType::File(c) => Some(c.source.clone()),
+        _ => None,
+    }
+}
+
+/// The user's display name in `room`, falling back to their user id. The sdk has
+/// already applied this sync's member events by the time handlers run, so renames
+/// show up on the very next message.
+async fn sender_label(room: &Room, user_id: &UserId) -> String {
+    match room.get_member_no_sync(user_id).await {
+        Ok(Some(member)) => user_label(
+            member.display_name(),
+            user_id.as_str(),
+            member.name_ambiguous(),
+        ),
+        _ => user_id.to_string(),
+    }
+}
+
+/// How `user_id` is named on their messages, from a room we share with them;
+/// presence isn't tied to a room, so any of them will do. The room they were found
+/// in last time is tried first, and every joined room only if they've left it.
+async fn presence_label(
+    client: &Client,
+    shared_rooms: &Mutex<HashMap<OwnedUserId, OwnedRoomId>>,
+    user_id: &UserId,
+) -> String {
+    let last = shared_rooms
+        .lock()
+        .unwrap()
+        .get(user_id)
+        .and_then(|room_id| client.get_room(room_id))
+        .filter(|room| room.state() == RoomState::Joined);
+
+    for room in last.into_iter().chain(client.joined_rooms()) {
+        if let Ok(Some(member)) = room.get_member_no_sync(user_id).await {
+            shared_rooms
+                .lock()
+                .unwrap()
+                .insert(user_id.to_owned(), room.room_id().to_owned());
+            return user_label(
+                member.display_name(),
+                user_id.as_str(),
+                member.name_ambiguous(),
+            );
+        }
+    }
+    user_id.to_string()
+}
+
+/// A message from history (search results, `/context`) in the same shape as a live one;
+/// anything other than a plain message, including events that couldn't be decrypted, is skipped
+async fn timeline_message(room: &Room, event: AnySyncTimelineEvent) -> Option<ChatMessage> {
+    let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncMessageLikeEvent::Original(ev),
+    )) = event
+    else {
+        return None;
+    };
+
+    let origin = if ev.sender == room.own_user_id() {
+        MessageOrigin::OwnDevice { device: None }
+    } else {
+        MessageOrigin::Remote { device: None }
+    };
+
+    let from = sender_label(room, &ev.sender).await;
+    match message_to_chat_event(ev, room, from, origin) {
+        ChatEvent::Message(message) => Some(message),
+        _ => None,
+    }
+}
+
+/// Who a direct message room is with, to name it after them. Rooms that were given
+/// a name of their own keep it.
+fn dm_partner(room: &Room) -> Option<String> {
+    let targets = room.direct_targets();
+    if targets.is_empty() || room.name().is_some() {
+        return None;
+    }
+
+    let partner = targets.iter().next()?;
+    let name = room
+        .heroes()
+        .into_iter()
+        .find(|hero| partner.as_user_id() == Some(&*hero.user_id))
+        .and_then(|hero| hero.display_name);
+
+    Some(name.unwrap_or_else(|| partner.to_string()))
+}
+
+fn push_attention(actions: &[Action]) -> Attention {
+    if actions.iter().any(Action::is_highlight) {
+        Attention::Highlight
+    } else if actions.iter().any(Action::should_notify) {
+        Attention::Notify
+    } else {
+        Attention::None
+    }
+}
+
+fn parse_user_id(user: &str) -> anyhow::Result<OwnedUserId> {
+    OwnedUserId::try_from(user)
+        .with_context(|| format!("'{}' is not a valid user id (e.g. @bob:matrix.org)", user))
+}
+
+/// The power levels we check admin commands against, so a missing permission gets a
+/// clear error up front instead of an opaque rejection from the server
+async fn room_power_levels(room: &Room) -> anyhow::Result<RoomPowerLevels> {
+    room.power_levels()
+        .await
+        .context("this room's power levels aren't known yet, try again after the next sync")
+}
+
+fn require_power(
+    levels: &RoomPowerLevels,
+    user_id: &UserId,
+    required: Int,
+    action: &str,
+) -> anyhow::Result<()> {
+    let own = levels.for_user(user_id);
+    if own < UserPowerLevel::Int(required) {
+        anyhow::bail!(
+            "permission denied: {} needs power level {} in this room, you have {}",
+            action,
+            required,
+            power_level(own)
+        );
+    }
+    Ok(())
+}
+
+/// Kicking, banning and changing someone's level only work on members ranked below us
+fn require_outranks(
+    levels: &RoomPowerLevels,
+    user_id: &UserId,
+    target: &UserId,
+    action: &str,
+) -> anyhow::Result<()> {
+    let own = levels.for_user(user_id);
+    let theirs = levels.for_user(target);
+    if theirs >= own {
+        anyhow::bail!(
+            "permission denied: can't {} {}, their power level ({}) isn't below yours ({})",
+            action,
+            target,
+            power_level(theirs),
+            power_level(own)
+        );
+    }
+    Ok(())
+}
+
+fn power_level(level: UserPowerLevel) -> PowerLevel {
+    match level {
+        UserPowerLevel::Int(level) => PowerLevel::Level(level.into()),
+        _ => PowerLevel::Creator,
+    }
+}
+
+/// Message ids from the session are event ids, except for local echoes the server
+/// hasn't confirmed yet
+fn event_id(id: &MessageId) -> anyhow::Result<OwnedEventId> {
+    OwnedEventId::try_from(id.as_str()).with_context(|| {
+        format!(
+            "'{}' is not a Matrix event id; if you just sent it, wait until it's delivered",
+            id
+        )
+    })
+}
+
+fn message_to_chat_event(
+    ev: OriginalSyncRoomMessageEvent,
+    room: &Room,
+    from: String,
+    origin: MessageOrigin,
+) -> ChatEvent {
+    // media events carry their filename in `body` unless a separate caption was given
+    let media =
+        |kind, caption: Option<&str>, name: &str, size: Option<UInt>, mime: Option<&String>| {
+            let attachment = Attachment {
+                kind,
+                name: name.to_string(),
+                size: size.map(u64::from),
+                mime: mime.cloned(),
+            };
+            (
+                MessageKind::Text,
+                caption.unwrap_or_default().to_string(),
+                Some(attachment),
+            )
+        };
+
+    let (in_reply_to, thread) = match &ev.content.relates_to {
+        Some(Relation::Reply(reply)) => (
+            Some(MessageId::new(reply.in_reply_to.event_id.to_string())),
+            None,
+        ),
+        Some(Relation::Thread(thread)) => {
+            // unless it's falling back, the reply points at a specific message in the thread
+            let reply = thread
+                .in_reply_to
+                .as_ref()
+                .filter(|_| !thread.is_falling_back)
+                .map(|reply| MessageId::new(reply.event_id.to_string()));
+            (reply, Some(MessageId::new(thread.event_id.to_string())))
+        }
+        _ => (None, None),
+    };
+
+    // older clients quote the original in the body of a reply; we show the reference instead
+    let text = |body: &str| match in_reply_to {
+        Some(_) => remove_plain_reply_fallback(body).to_string(),
+        None => body.to_string(),
+    };
+
+    let (kind, body, attachment) = match &ev.content.msgtype {
+        MessageType::Text(c) => (MessageKind::Text, text(&c.body), None),
+        MessageType::Notice(c) => (MessageKind::Notice, text(&c.body), None),
+        MessageType::Emote(c) => (MessageKind::Emote, text(&c.body), None),
+        MessageType::Image(c) => {
+            let info = c.info.as_deref();
+            media(
+                AttachmentKind::Image,
+                c.caption(),
+                c.filename(),
+                info.and_then(|i| i.size),
+                info.and_then(|i| i.mimetype.as_ref()),
+            )
+        }
+        MessageType::Audio(c) => {
+            let info = c.info.as_deref();
+            media(
+                AttachmentKind::Audio,
+                c.caption(),
+                c.filename(),
+                info.and_then(|i| i.size),
+                info.and_then(|i| i.mimetype.as_ref()),
+            )
+        }
+        MessageType::Video(c) => {
+            let info = c.info.as_deref();
+            media(
+                AttachmentKind::Video,
+                c.caption(),
+                c.filename(),
+                info.and_then(|i| i.size),
+                info.and_then(|i| i.mimetype.as_ref()),
+            )
+        }
+        MessageType::File(c) => {
+            let info = c.info.as_deref();
+            media(
+                AttachmentKind::File,
+                c.caption(),
+                c.filename(),
+                info.and_then(|i| i.size),
+                info.and_then(|i| i.mimetype.as_ref()),
+            )
+        }
+        // locations, server notices etc. all carry a plain-text fallback body
+        other => (MessageKind::Text, other.body().to_string(), None),
+    };
+
+    ChatEvent::Message(ChatMessage {
+        id: MessageId::new(ev.event_id.to_string()),
+        ts: to_utc(ev.origin_server_ts),
+        from,
+        room: RoomId::new(room.room_id().to_string()),
+        formatted: formatted(&ev.content.msgtype),
+        body,
+        kind,
+        attachment,
+        origin,
+        in_reply_to,
+        thread,
+        attention: Attention::None,
+    })
+}
+
+fn member_to_chat_event(
+    ev: &OriginalSyncRoomMemberEvent,
+    room: &Room,
+    by: String,
+) -> Option<ChatEvent> {
+    let reason = ev.content.reason.clone();
+
+    // leave events usually drop the display name, so fall back to the previous one
+    let display_name = ev
+        .content
+        .displayname
+        .as_deref()
+        .or_else(|| ev.prev_content().and_then(|c| c.displayname.as_deref()));
+    let mut user = user_label(display_name, ev.state_key.as_str(), false);
+
+    let change = match ev.membership_change() {
+        MatrixMembershipChange::Joined | MatrixMembershipChange::InvitationAccepted => {
+            MembershipChange::Joined
+        }
+        MatrixMembershipChange::Left => MembershipChange::Left,
+        MatrixMembershipChange::Invited => MembershipChange::Invited { by },
+        MatrixMembershipChange::Kicked => MembershipChange::Kicked { by, reason },
+        MatrixMembershipChange::Banned | MatrixMembershipChange::KickedAndBanned => {
+            MembershipChange::Banned { by, reason }
+        }
+        MatrixMembershipChange::Unbanned => MembershipChange::Unbanned { by },
+        MatrixMembershipChange::InvitationRejected => MembershipChange::InviteRejected,
+        MatrixMembershipChange::InvitationRevoked => MembershipChange::InviteRevoked { by },
+        MatrixMembershipChange::ProfileChanged {
+            displayname_change: Some(change),
+            ..
+        } => {
+            user = user_label(change.old, ev.state_key.as_str(), false);
+            MembershipChange::Renamed {
+                to: user_label(change.new, ev.state_key.as_str(), false),
+            }
+        }
+        // avatar changes, knocks and no-op state resends aren't worth a transcript line
+        _ => return None,
+    };
+
+    Some(ChatEvent::Membership {
+        ts: to_utc(ev.origin_server_ts),
+        room: RoomId::new(room.room_id().to_string()),
+        user,
+        change,
+    })
+}
+
+#[async_trait]
+impl ChatBackend for MatrixBackend {
+    fn own_user(&self) -> String {
+        self.client
+            .user_id()
+            .map(|user_id| user_id.to_string())
+            .unwrap_or_default()
+    }
+
+    async fn poll_events(&mut self) -> anyhow::Result<Vec<ChatEvent>> {
+        let mut events = Vec::new();
+
+        while let Ok(ev) = self.events_rx.try_recv() {
+            events.push(ev);
+        }
+
+        Ok(events)
+    }
+
+    async fn resolve_room(&mut self, room: &RoomId) -> anyhow::Result<RoomId> {
+        if let Ok(room_id) = <&MatrixRoomId>::try_from(room.as_str()) {
+            return Ok(RoomId::new(room_id.to_string()));
+        }
+
+        if let Some(known) = self.known_room(room) {
+            return Ok(RoomId::new(known.room_id().to_string()));
+        }
+
+        let alias = <&RoomAliasId>::try_from(room.as_str())
+            .with_context(|| format!("'{}' is not a valid room id or alias", room))?;
+
+        let resolved = self
+            .client
+            .resolve_room_alias(alias)
+            .await
+            .with_context(|| format!("couldn't resolve alias '{}'", room))?;
+
+        let id = RoomId::new(resolved.room_id.to_string());
+        self.via_servers.insert(resolved.room_id, resolved.servers);
+
+        Ok(id)
+    }
+
+    fn room_name(&self, room: &RoomId) -> Option<String> {
+        let room = self.known_room(room)?;
+
+        dm_partner(&room).or_else(|| room.cached_display_name().map(|name| name.to_string()))
+    }
+
+    async fn join_room(&mut self, room: &RoomId) -> anyhow::Result<()> {
+        let room_or_alias = RoomOrAliasId::parse(room.as_str())
+            .with_context(|| format!("'{}' is not a valid room id or alias", room))?;
+
+        let via = OwnedRoomId::try_from(room.as_str())
+            .ok()
+            .and_then(|room_id| self.via_servers.get(&room_id).cloned())
+            .unwrap_or_default();
+
+        let joined = self
+            .client
+            .join_room_by_id_or_alias(&room_or_alias, &via)
+            .await;
+
+        match joined {
+            Err(e)
+                if self.read_only && e.client_api_error_kind() == Some(&ErrorKind::Forbidden) =>
+            {
+                anyhow::bail!("'{}' doesn't allow guests to join", room)
+            }
+            joined => joined.with_context(|| format!("failed to join '{}'", room))?,
+        };
+
+        Ok(())
+    }
+
+    async fn leave_room(&mut self, room: &RoomId) -> anyhow::Result<()> {
+        let matrix_room = self.joined_room(room)?;
+
+        matrix_room.leave().await?;
+
+        Ok(())
+    }
+
+    async fn send_message(&mut self, room: &RoomId, body: &str) -> anyhow::Result<Uuid> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let id = Uuid::new_v4();
+
+        self.enqueue(QueuedMessage {
+            id,
+            room: matrix_room,
+            body: body.to_string(),
+            reply_to: None,
+            thread: None,
+            kind: MessageKind::Text,
+        });
+
+        Ok(id)
+    }
+
+    async fn send_reply(
+        &mut self,
+        room: &RoomId,
+        to: &MessageId,
+        body: &str,
+    ) -> anyhow::Result<Uuid> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let reply_to = event_id(to)?;
+        let id = Uuid::new_v4();
+
+        self.enqueue(QueuedMessage {
+            id,
+            room: matrix_room,
+            body: body.to_string(),
+            reply_to: Some(reply_to),
+            thread: None,
+            kind: MessageKind::Text,
+        });
+
+        Ok(id)
+    }
+
+    fn supports_threads(&self) -> bool {
+        true
+    }
+
+    async fn send_to_thread(
+        &mut self,
+        room: &RoomId,
+        root: &MessageId,
+        reply_to: Option<&MessageId>,
+        body: &str,
+    ) -> anyhow::Result<Uuid> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let thread = event_id(root)?;
+        let reply_to = reply_to.map(event_id).transpose()?;
+        let id = Uuid::new_v4();
+
+        self.enqueue(QueuedMessage {
+            id,
+            room: matrix_room,
+            body: body.to_string(),
+            reply_to,
+            thread: Some(thread),
+            kind: MessageKind::Text,
+        });
+
+        Ok(id)
+    }
+
+    async fn edit_message(
+        &mut self,
+        room: &RoomId,
+        id: &MessageId,
+        body: &str,
+    ) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let event_id = event_id(id)?;
+
+        // checks the original is ours and builds the m.replace relation with its fallback body
+        let content = body_content(MessageKind::Text, body);
+        let edit = matrix_room
+            .make_edit_event(&event_id, EditedContent::RoomMessage(content))
+            .await
+            .with_context(|| format!("can't edit {}", id))?;
+
+        matrix_room.send(edit).await?;
+
+        Ok(())
+    }
+
+    async fn delete_message(
+        &mut self,
+        room: &RoomId,
+        id: &MessageId,
+        reason: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let event_id = event_id(id)?;
+
+        matrix_room
+            .redact(&event_id, reason, None)
+            .await
+            .with_context(|| format!("can't delete {}", id))?;
+
+        Ok(())
+    }
+
+    async fn react(&mut self, room: &RoomId, id: &MessageId, key: &str) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let content = ReactionEventContent::new(Annotation::new(event_id(id)?, key.to_string()));
+
+        matrix_room
+            .send(content)
+            .await
+            .with_context(|| format!("can't react to {}", id))?;
+        Ok(())
+    }
+
+    async fn unreact(&mut self, room: &RoomId, id: &MessageId, key: &str) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let own_user_id = self.client.user_id().context("client has no user_id")?;
+
+        // the server knows our reactions from before this session too; in an encrypted
+        // room they're encrypted, so they can only be told apart once decrypted here
+        let mut options = RelationsOptions {
+            include_relations: IncludeRelations::RelationsOfType(RelationType::Annotation),
+            limit: Some(UInt::from(RELATIONS_PAGE_SIZE)),
+            ..Default::default()
+        };
+        let reaction_id = loop {
+            let page = matrix_room
+                .relations(event_id(id)?, options.clone())
+                .await
+                .with_context(|| format!("can't look up the reactions to {}", id))?;
+
+            let ours = page.chunk.iter().find_map(|event| {
+                let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::Reaction(
+                    SyncMessageLikeEvent::Original(reaction),
+                ))) = event.raw().deserialize()
+                else {
+                    return None;
+                };
+                (reaction.sender == own_user_id && reaction.content.relates_to.key == key)
+                    .then_some(reaction.event_id)
+            });
+
+            match (ours, page.next_batch_token) {
+                (Some(reaction_id), _) => break reaction_id,
+                (None, Some(token)) => options.from = Some(token),
+                (None, None) => anyhow::bail!("you haven't reacted to {} with {}", id, key),
+            }
+        };
+
+        matrix_room
+            .redact(&reaction_id, None, None)
+            .await
+            .with_context(|| format!("can't take back the reaction on {}", id))?;
+        Ok(())
+    }
+
+    async fn retry_failed(&mut self, id: Option<&Uuid>) -> anyhow::Result<usize> {
+        let retry: Vec<QueuedMessage> = {
+            let mut failed = self.failed_sends.lock().unwrap();
+            let (retry, keep) = failed
+                .drain(..)
+                .partition(|message| id.is_none_or(|id| message.id == *id));
+            *failed = keep;
+            retry
+        };
+
+        if let (Some(id), true) = (id, retry.is_empty()) {
+            anyhow::bail!("no failed message with id {}", id);
+        }
+
+        let count = retry.len();
+        for message in retry {
+            self.enqueue(message);
+        }
+
+        Ok(count)
+    }
+
+    async fn send_attachment(&mut self, room: &RoomId, path: &Path) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .with_context(|| format!("'{}' has no usable file name", path.display()))?
+            .to_string();
+
+        let data = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("failed to read '{}'", path.display()))?;
+
+        // matrix-sdk picks m.image/m.audio/m.video/m.file from the mime type,
+        // and encrypts the upload itself when the room is encrypted
+        let mime = mime_guess::from_path(path).first_or_octet_stream();
+
+        matrix_room
+            .send_attachment(
+                name,
+                &mime,
+                data,
+                AttachmentConfig::new().txn_id(self.track_send(TransactionId::new())),
+            )
+            .await
+            .with_context(|| format!("failed to upload '{}'", path.display()))?;
+
+        Ok(())
+    }
+
+    async fn download_attachment(
+        &mut self,
+        id: &MessageId,
+        dest: Option<&Path>,
+    ) -> anyhow::Result<PathBuf> {
+        let (name, source) = {
+            let media = self.media.lock().unwrap();
+            let stored = media
+                .iter()
+                .rev()
+                .find(|stored| stored.id == *id)
+                .with_context(|| format!("no attachment received with id {}", id))?;
+            (stored.name.clone(), stored.source.clone())
+        };
+
+        // the name comes from the sender, so never let it point outside the destination
+        let file_name = Path::new(&name)
+            .file_name()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(id.to_string()));
+
+        let path = match dest {
+            Some(dest) if dest.is_dir() => dest.join(file_name),
+            Some(dest) => dest.to_path_buf(),
+            None => file_name,
+        };
+
+        let request = MediaRequestParameters {
+            source,
+            format: MediaFormat::File,
+        };
+
+        // encrypted sources are decrypted by the sdk using the key carried in the event
+        let data = self
+            .client
+            .media()
+            .get_media_content(&request, true)
+            .await
+            .context("failed to fetch attachment from the homeserver")?;
+
+        // never overwrite something already there, which may not even be an earlier download
+        let opened = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await;
+        let mut file = match opened {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                anyhow::bail!(
+                    "'{}' already exists; give a path to save it as",
+                    path.display()
+                )
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to write '{}'", path.display()))
+            }
+        };
+        file.write_all(&data)
+            .await
+            .with_context(|| format!("failed to write '{}'", path.display()))?;
+
+        Ok(path)
+    }
+
+    async fn create_room(&mut self, room: &NewRoom) -> anyhow::Result<RoomId> {
+        self.ensure_writable()?;
+        let mut request = create_room::v3::Request::new();
+        request.name = Some(room.name.clone());
+
+        if room.public {
+            request.preset = Some(create_room::v3::RoomPreset::PublicChat);
+            request.visibility = Visibility::Public;
+        } else {
+            request.preset = Some(create_room::v3::RoomPreset::PrivateChat);
+            request.visibility = Visibility::Private;
+        }
+
+        if room.encrypted {
+            let encryption = InitialStateEvent::with_empty_state_key(
+                RoomEncryptionEventContent::with_recommended_defaults(),
+            );
+            request.initial_state.push(encryption.to_raw_any());
+        }
+
+        let created = self
+            .client
+            .create_room(request)
+            .await
+            .with_context(|| format!("failed to create room '{}'", room.name))?;
+
+        Ok(RoomId::new(created.room_id().to_string()))
+    }
+
+    async fn invite_user(&mut self, room: &RoomId, user: &str) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+
+        let user_id = <&UserId>::try_from(user)
+            .with_context(|| format!("'{}' is not a valid user id (e.g. @bob:matrix.org)", user))?;
+
+        matrix_room
+            .invite_user_by_id(user_id)
+            .await
+            .with_context(|| format!("failed to invite {} to '{}'", user, room))?;
+
+        Ok(())
+    }
+
+    async fn pending_invites(&mut self) -> anyhow::Result<Vec<RoomInvite>> {
+        let mut invites = Vec::new();
+
+        for room in self.client.invited_rooms() {
+            let inviter = room
+                .invite_details()
+                .await
+                .ok()
+                .map(|details| details.inviter_id.to_string());
+
+            invites.push(RoomInvite {
+                room: RoomId::new(room.room_id().to_string()),
+                name: room.name(),
+                inviter,
+            });
+        }
+
+        Ok(invites)
+    }
+
+    async fn accept_invite(&mut self, room: &RoomId) -> anyhow::Result<()> {
+        let matrix_room = self.invited_room(room)?;
+
+        join_invited_room(&matrix_room)
+            .await
+            .with_context(|| format!("failed to accept invite to '{}'", room))?;
+
+        Ok(())
+    }
+
+    async fn update_room(&mut self, room: &RoomId, setting: &RoomSetting) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let levels = room_power_levels(&matrix_room).await?;
+
+        let (event_type, action) = match setting {
+            RoomSetting::Name(_) => (StateEventType::RoomName, "renaming the room"),
+            RoomSetting::Topic(_) => (StateEventType::RoomTopic, "changing the topic"),
+            RoomSetting::Avatar(_) => (StateEventType::RoomAvatar, "changing the avatar"),
+            RoomSetting::JoinRule(_) => (StateEventType::RoomJoinRules, "changing the join rule"),
+            RoomSetting::HistoryVisibility(_) => (
+                StateEventType::RoomHistoryVisibility,
+                "changing history visibility",
+            ),
+        };
+        require_power(
+            &levels,
+            matrix_room.own_user_id(),
+            levels.for_state(event_type),
+            action,
+        )?;
+
+        match setting {
+            RoomSetting::Name(name) => {
+                matrix_room.set_name(name.clone()).await?;
+            }
+            RoomSetting::Topic(topic) => {
+                matrix_room.set_room_topic(topic).await?;
+            }
+            RoomSetting::Avatar(path) => {
+                let data = tokio::fs::read(path)
+                    .await
+                    .with_context(|| format!("failed to read '{}'", path.display()))?;
+                let mime = mime_guess::from_path(path).first_or_octet_stream();
+                if mime.type_() != mime_guess::mime::IMAGE {
+                    anyhow::bail!("'{}' doesn't look like an image", path.display());
+                }
+                matrix_room.upload_avatar(&mime, data, None).await?;
+            }
+            RoomSetting::JoinRule(rule) => {
+                let rule = match rule {
+                    JoinRule::Public => MatrixJoinRule::Public,
+                    JoinRule::Invite => MatrixJoinRule::Invite,
+                    JoinRule::Knock => MatrixJoinRule::Knock,
+                };
+                matrix_room
+                    .send_state_event(RoomJoinRulesEventContent::new(rule))
+                    .await?;
+            }
+            RoomSetting::HistoryVisibility(visibility) => {
+                let visibility = match visibility {
+                    HistoryVisibility::WorldReadable => MatrixHistoryVisibility::WorldReadable,
+                    HistoryVisibility::Shared => MatrixHistoryVisibility::Shared,
+                    HistoryVisibility::Invited => MatrixHistoryVisibility::Invited,
+                    HistoryVisibility::Joined => MatrixHistoryVisibility::Joined,
+                };
+                matrix_room
+                    .send_state_event(RoomHistoryVisibilityEventContent::new(visibility))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn kick_user(
+        &mut self,
+        room: &RoomId,
+        user: &str,
+        reason: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let user_id = parse_user_id(user)?;
+        let levels = room_power_levels(&matrix_room).await?;
+
+        require_power(&levels, matrix_room.own_user_id(), levels.kick, "kicking")?;
+        require_outranks(&levels, matrix_room.own_user_id(), &user_id, "kick")?;
+
+        matrix_room
+            .kick_user(&user_id, reason)
+            .await
+            .with_context(|| format!("failed to kick {}", user))?;
+
+        Ok(())
+    }
+
+    async fn ban_user(
+        &mut self,
+        room: &RoomId,
+        user: &str,
+        reason: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let user_id = parse_user_id(user)?;
+        let levels = room_power_levels(&matrix_room).await?;
+
+        require_power(&levels, matrix_room.own_user_id(), levels.ban, "banning")?;
+        require_outranks(&levels, matrix_room.own_user_id(), &user_id, "ban")?;
+
+        matrix_room
+            .ban_user(&user_id, reason)
+            .await
+            .with_context(|| format!("failed to ban {}", user))?;
+
+        Ok(())
+    }
+
+    async fn unban_user(&mut self, room: &RoomId, user: &str) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let user_id = parse_user_id(user)?;
+        let levels = room_power_levels(&matrix_room).await?;
+
+        // lifting a ban lets them back in, which the spec treats as both a ban and a kick
+        let required = levels.ban.max(levels.kick);
+        require_power(&levels, matrix_room.own_user_id(), required, "unbanning")?;
+
+        matrix_room
+            .unban_user(&user_id, None)
+            .await
+            .with_context(|| format!("failed to unban {}", user))?;
+
+        Ok(())
+    }
+
+    async fn power_levels(&mut self, room: &RoomId) -> anyhow::Result<PowerLevels> {
+        let matrix_room = self.joined_room(room)?;
+        let levels = room_power_levels(&matrix_room).await?;
+
+        let mut users: Vec<(String, PowerLevel)> = levels
+            .users
+            .keys()
+            .chain(levels.rules.privileged_creators.iter().flatten())
+            .map(|user_id| (user_id.to_string(), power_level(levels.for_user(user_id))))
+            .collect();
+        users.sort_by(|(a_user, a_level), (b_user, b_level)| {
+            b_level.cmp(a_level).then_with(|| a_user.cmp(b_user))
+        });
+        users.dedup();
+
+        Ok(PowerLevels {
+            own: power_level(levels.for_user(matrix_room.own_user_id())),
+            users,
+            users_default: levels.users_default.into(),
+            events_default: levels.events_default.into(),
+            state_default: levels.state_default.into(),
+            invite: levels.invite.into(),
+            kick: levels.kick.into(),
+            ban: levels.ban.into(),
+            redact: levels.redact.into(),
+        })
+    }
+
+    async fn set_power_level(
+        &mut self,
+        room: &RoomId,
+        user: &str,
+        level: i64,
+    ) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let user_id = parse_user_id(user)?;
+        let new_level =
+            Int::new(level).with_context(|| format!("{} is not a valid power level", level))?;
+        let levels = room_power_levels(&matrix_room).await?;
+        let own_user_id = matrix_room.own_user_id();
+
+        require_power(
+            &levels,
+            own_user_id,
+            levels.for_state(StateEventType::RoomPowerLevels),
+            "changing power levels",
+        )?;
+
+        let own = levels.for_user(own_user_id);
+        if UserPowerLevel::Int(new_level) > own {
+            anyhow::bail!(
+                "permission denied: can't raise anyone above your own power level ({})",
+                power_level(own)
+            );
+        }
+        // lowering yourself is always allowed, anyone else has to rank below you
+        if *user_id != *own_user_id {
+            require_outranks(&levels, own_user_id, &user_id, "change the power level of")?;
+        }
+
+        matrix_room
+            .update_power_levels(vec![(&user_id, new_level)])
+            .await
+            .with_context(|| format!("failed to change the power level of {}", user))?;
+
+        Ok(())
+    }
+
+    async fn set_display_name(&mut self, name: &str, room: Option<&RoomId>) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let Some(room) = room else {
+            self.client
+                .account()
+                .set_display_name(Some(name))
+                .await
+                .context("failed to set display name")?;
+            return Ok(());
+        };
+
+        let matrix_room = self.joined_room(room)?;
+        let own_user_id = self
+            .client
+            .user_id()
+            .context("client has no user_id")?
+            .to_owned();
+
+        // a per-room name is just our own member event re-sent with a different
+        // displayname, so keep the avatar we already have in this room
+        let avatar_url = matrix_room
+            .get_member_no_sync(&own_user_id)
+            .await?
+            .and_then(|member| member.avatar_url().map(ToOwned::to_owned));
+
+        let mut content = RoomMemberEventContent::new(MembershipState::Join);
+        content.displayname = Some(name.to_string());
+        content.avatar_url = avatar_url;
+
+        matrix_room
+            .send_state_event_for_key(&own_user_id, content)
+            .await
+            .with_context(|| format!("failed to set display name in '{}'", room))?;
+
+        Ok(())
+    }
+
+    async fn search_messages(
+        &mut self,
+        terms: &str,
+        room: Option<&RoomId>,
+    ) -> anyhow::Result<Vec<SearchHit>> {
+        let scope = room.map(|room| self.joined_room(room)).transpose()?;
+        let mut server_hits = Vec::new();
+
+        // no point asking the server when the only room to search is encrypted
+        if scope
+            .as_ref()
+            .is_none_or(|room| !room.encryption_state().is_encrypted())
+        {
+            let mut criteria = Criteria::new(terms.to_string());
+            criteria.order_by = Some(OrderBy::Rank);
+            if let Some(room) = &scope {
+                criteria.filter.rooms = Some(vec![room.room_id().to_owned()]);
+            }
+            let mut categories = Categories::new();
+            categories.room_events = Some(criteria);
+
+            // what the local index finds is still worth showing without the server's
+            let results = match self
+                .client
+                .send(search_events::v3::Request::new(categories))
+                .await
+            {
+                Ok(response) => response.search_categories.room_events.results,
+                Err(e) => {
+                    let note = format!("server search failed, showing encrypted rooms only: {}", e);
+                    report(&self.events_tx, ChatEvent::System(note));
+                    Vec::new()
+                }
+            };
+
+            for result in results {
+                let Some(event) = result.result.and_then(|raw| raw.deserialize().ok()) else {
+                    continue;
+                };
+                let Some(room) = self.client.get_room(event.room_id()) else {
+                    continue;
+                };
+                if let Some(message) = timeline_message(&room, event.into()).await {
+                    server_hits.push(SearchHit {
+                        message,
+                        rank: result.rank,
+                    });
+                }
+            }
+        }
+
+        let local_hits = self
+            .search_index
+            .lock()
+            .unwrap()
+            .search(terms, room, SEARCH_LIMIT);
+
+        // the server's scores and the index's word counts aren't on the same scale
+        Ok(merge_ranked(server_hits, local_hits, SEARCH_LIMIT))
+    }
+
+    async fn message_context(
+        &mut self,
+        room: &RoomId,
+        id: &MessageId,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        let matrix_room = self
+            .known_room(room)
+            .with_context(|| format!("unknown room '{}'", room))?;
+        let event_id = event_id(id)?;
+
+        let context = matrix_room
+            .event_with_context(&event_id, true, UInt::from(CONTEXT_SIZE), None)
+            .await
+            .with_context(|| format!("couldn't load {}", id))?;
+
+        // the server lists the earlier events newest first
+        let events = context
+            .events_before
+            .into_iter()
+            .rev()
+            .chain(context.event)
+            .chain(context.events_after);
+
+        let mut messages = Vec::new();
+        for event in events {
+            let Ok(event) = event.raw().deserialize() else {
+                continue;
+            };
+            if let Some(message) = timeline_message(&matrix_room, event).await {
+                messages.push(message);
+            }
+        }
+
+        Ok(messages)
+    }
+
+    async fn open_dm(&mut self, user: &str) -> anyhow::Result<RoomId> {
+        let user_id = parse_user_id(user)?;
+        if self.client.user_id() == Some(&*user_id) {
+            anyhow::bail!("can't start a direct message with yourself");
+        }
+
+        // m.direct can list several rooms with the same person; any joined one will do
+        let room = match self.client.get_dm_room(&user_id) {
+            Some(room) => room,
+            None => {
+                self.ensure_writable()?;
+                self.client
+                    .create_dm(&user_id)
+                    .await
+                    .with_context(|| format!("couldn't start a direct message with {}", user))?
+            }
+        };
+
+        Ok(RoomId::new(room.room_id().to_string()))
+    }
+
+    async fn send_notice(&mut self, room: &RoomId, body: &str) -> anyhow::Result<Uuid> {
+        self.ensure_writable()?;
+        let matrix_room = self.joined_room(room)?;
+        let id = Uuid::new_v4();
+
+        self.enqueue(QueuedMessage {
+            id,
+            room: matrix_room,
+            body: body.to_string(),
+            reply_to: None,
+            thread: None,
+            kind: MessageKind::Notice,
+        });
+
+        Ok(id)
+    }
+
+    fn is_direct(&self, room: &RoomId) -> bool {
+        self.known_room(room)
+            .is_some_and(|room| !room.direct_targets().is_empty())
+    }
+
+    async fn send_receipt(&mut self, room: &RoomId, id: &MessageId) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let event_id = event_id(id)?;
+
+        // the fully-read marker is room account data, so the server keeps it for
+        // every device and the next session; the receipt tells everyone else
+        let receipts = Receipts::new()
+            .fully_read_marker(event_id.clone())
+            .public_read_receipt(event_id);
+        self.joined_room(room)?
+            .send_multiple_receipts(receipts)
+            .await
+            .context("failed to send read receipt")?;
+        Ok(())
+    }
+
+    async fn send_typing(&mut self, room: &RoomId, typing: bool) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+
+        // the sdk also holds back repeats of a notice the server still has
+        self.joined_room(room)?
+            .typing_notice(typing)
+            .await
+            .context("failed to send typing notice")?;
+        Ok(())
+    }
+
+    async fn set_presence(&mut self, presence: &Presence) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+        let user_id = self
+            .client
+            .user_id()
+            .context("client has no user_id")?
+            .to_owned();
+
+        let state = match presence.state {
+            PresenceState::Online => MatrixPresenceState::Online,
+            PresenceState::Away => MatrixPresenceState::Unavailable,
+            PresenceState::Offline => MatrixPresenceState::Offline,
+        };
+
+        let mut request = set_presence::v3::Request::new(user_id, state.clone());
+        request.status_msg = presence.message.clone();
+        self.client
+            .send(request)
+            .await
+            .context("failed to set presence")?;
+
+        *self.sync_presence.lock().unwrap() = state;
+        Ok(())
+    }
+
+    async fn list_members(&mut self, room: &RoomId) -> anyhow::Result<Vec<String>> {
+        let members = self
+            .joined_room(room)?
+            .members(RoomMemberships::JOIN)
+            .await
+            .context("failed to fetch the member list")?;
+
+        Ok(members
+            .iter()
+            .map(|member| {
+                user_label(
+                    member.display_name(),
+                    member.user_id().as_str(),
+                    member.name_ambiguous(),
+                )
+            })
+            .collect())
+    }
+
+    async fn set_notify_level(&mut self, room: &RoomId, level: NotifyLevel) -> anyhow::Result<()> {
+        let matrix_room = self.joined_room(room)?;
+
+        let mode = match level {
+            NotifyLevel::All => RoomNotificationMode::AllMessages,
+            NotifyLevel::Mentions => RoomNotificationMode::MentionsAndKeywordsOnly,
+            NotifyLevel::Muted => RoomNotificationMode::Mute,
+        };
+
+        // stored as push rules on the server, so other clients and devices follow it too
+        self.client
+            .notification_settings()
+            .await
+            .set_room_notification_mode(matrix_room.room_id(), mode)
+            .await
+            .with_context(|| format!("couldn't change notifications for '{}'", room))?;
+
+        Ok(())
+    }
+
+    async fn list_rooms(&mut self) -> anyhow::Result<Vec<RoomSummary>> {
+        let mut rooms = Vec::new();
+
+        for room in self.client.joined_rooms() {
+            let name = match dm_partner(&room) {
+                Some(partner) => partner,
+                None => room
+                    .display_name()
+                    .await
+                    .map(|name| name.to_string())
+                    .unwrap_or_else(|_| room.room_id().to_string()),
+            };
+
+            let counts = room.unread_notification_counts();
+            rooms.push(RoomSummary {
+                room: RoomId::new(room.room_id().to_string()),
+                name,
+                alias: room.canonical_alias().map(|alias| alias.to_string()),
+                members: room.joined_members_count(),
+                unread: counts.notification_count,
+                highlights: counts.highlight_count,
+                notify: match room.notification_mode().await {
+                    Some(RoomNotificationMode::MentionsAndKeywordsOnly) => NotifyLevel::Mentions,
+                    Some(RoomNotificationMode::Mute) => NotifyLevel::Muted,
+                    _ => NotifyLevel::All,
+                },
+                direct: !room.direct_targets().is_empty(),
+            });
+        }
+
+        rooms.sort_by_key(|room| room.name.to_lowercase());
+
+        Ok(rooms)
+    }
+
+    async fn search_directory(
+        &mut self,
+        query: Option<&str>,
+        server: Option<&str>,
+    ) -> anyhow::Result<Vec<DirectoryRoom>> {
+        let mut request = get_public_rooms_filtered::v3::Request::new();
+        request.limit = Some(UInt::from(20u32));
+
+        let mut filter = Filter::new();
+        filter.generic_search_term = query.map(str::to_string);
+        request.filter = filter;
+
+        if let Some(server) = server {
+            let server = OwnedServerName::try_from(server)
+                .with_context(|| format!("'{}' is not a valid server name", server))?;
+            request.server = Some(server);
+        }
+
+        let response = self
+            .client
+            .public_rooms_filtered(request)
+            .await
+            .context("room directory search failed")?;
+
+        let rooms = response
+            .chunk
+            .into_iter()
+            .map(|chunk| DirectoryRoom {
+                room: RoomId::new(chunk.room_id.to_string()),
+                name: chunk.name,
+                alias: chunk.canonical_alias.map(|alias| alias.to_string()),
+                topic: chunk.topic,
+                members: chunk.num_joined_members.into(),
+            })
+            .collect();
+
+        Ok(rooms)
+    }
+
+    async fn decline_invite(&mut self, room: &RoomId) -> anyhow::Result<()> {
+        let matrix_room = self.invited_room(room)?;
+
+        matrix_room
+            .leave()
+            .await
+            .with_context(|| format!("failed to decline invite to '{}'", room))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sso_redirect_yields_the_login_token() {
+        assert_eq!(
+            sso_login_token("GET /?loginToken=abc%2Bdef HTTP/1.1\r\n").as_deref(),
+            Some("abc+def")
+        );
+        assert_eq!(sso_login_token("GET /favicon.ico HTTP/1.1\r\n"), None);
+        assert_eq!(sso_login_token(""), None);
+    }
+
+    #[test]
+    fn sync_backoff_doubles_up_to_the_cap() {
+        assert_eq!(sync_backoff(1), Duration::from_secs(1));
+        assert_eq!(sync_backoff(2), Duration::from_secs(2));
+        assert_eq!(sync_backoff(4), Duration::from_secs(8));
+        assert_eq!(sync_backoff(7), MAX_SYNC_BACKOFF);
+        assert_eq!(sync_backoff(u32::MAX), MAX_SYNC_BACKOFF);
     }
 }
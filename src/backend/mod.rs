@@ -1,20 +1,285 @@
+mod html;
 pub mod matrix;
 pub mod p2p;
+mod search_index;
 
-use crate::protocol::{ChatEvent, RoomId};
+use std::path::{Path, PathBuf};
+
+use crate::protocol::{
+    ChatEvent, ChatMessage, DirectoryRoom, MessageId, NewRoom, NotifyLevel, PowerLevels, Presence,
+    RoomId, RoomInvite, RoomSetting, RoomSummary, SearchHit,
+};
 use async_trait::async_trait;
+use tokio::sync::mpsc;
+use uuid::Uuid;
 
 #[async_trait]
-pub trait ChatBackend {
+pub trait ChatBackend: Send {
+    /// map whatever the user typed for a room (id, alias, name) to the id the backend
+    /// uses for it everywhere else, including on incoming events
+    async fn resolve_room(&mut self, room: &RoomId) -> anyhow::Result<RoomId> {
+        Ok(room.clone())
+    }
+
+    /// who we are on this backend, as shown on our own messages
+    fn own_user(&self) -> String;
+
+    /// a human-readable name for a room, if the backend knows one
+    fn room_name(&self, _room: &RoomId) -> Option<String> {
+        None
+    }
+
     /// tell the backend which room/channel to use
     async fn join_room(&mut self, room: &RoomId) -> anyhow::Result<()>;
 
-    /// send a message to the active room
-    async fn send_message(&mut self, room: &RoomId, body: &str) -> anyhow::Result<()>;
+    /// send a message to the active room, returning the id its `SendStatus` events
+    /// will carry. Backends may queue the message and deliver it later.
+    async fn send_message(&mut self, room: &RoomId, body: &str) -> anyhow::Result<Uuid>;
+
+    /// reply to message `to` in the room; like `send_message`, the returned id is what
+    /// its `SendStatus` events carry
+    async fn send_reply(
+        &mut self,
+        _room: &RoomId,
+        _to: &MessageId,
+        _body: &str,
+    ) -> anyhow::Result<Uuid> {
+        anyhow::bail!("this backend doesn't support replies")
+    }
+
+    /// post a message in the thread under `root`, as a reply to `reply_to` within it if given;
+    /// the returned id is what its `SendStatus` events carry
+    async fn send_to_thread(
+        &mut self,
+        _room: &RoomId,
+        _root: &MessageId,
+        _reply_to: Option<&MessageId>,
+        _body: &str,
+    ) -> anyhow::Result<Uuid> {
+        anyhow::bail!("this backend doesn't support threads")
+    }
+
+    /// whether `send_to_thread` works here, so `/thread` can say no up front
+    fn supports_threads(&self) -> bool {
+        false
+    }
+
+    /// replace the text of one of our messages; the change arrives back as `ChatEvent::Edited`
+    async fn edit_message(
+        &mut self,
+        _room: &RoomId,
+        _id: &MessageId,
+        _body: &str,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support editing messages")
+    }
+
+    /// delete a message; the deletion arrives back as `ChatEvent::Redacted`
+    async fn delete_message(
+        &mut self,
+        _room: &RoomId,
+        _id: &MessageId,
+        _reason: Option<&str>,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support deleting messages")
+    }
+
+    /// react to message `id` with `key`, usually an emoji; like everyone else's, the
+    /// reaction arrives back as `ChatEvent::Reaction`
+    async fn react(&mut self, _room: &RoomId, _id: &MessageId, _key: &str) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support reactions")
+    }
+
+    /// take back our `key` reaction on message `id`
+    async fn unreact(&mut self, _room: &RoomId, _id: &MessageId, _key: &str) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support reactions")
+    }
+
+    /// queue failed messages again, all of them or just `id`, returning how many were requeued
+    async fn retry_failed(&mut self, _id: Option<&Uuid>) -> anyhow::Result<usize> {
+        Ok(0)
+    }
 
     /// query the backend for changes
     async fn poll_events(&mut self) -> anyhow::Result<Vec<ChatEvent>>;
 
     /// tell the backend to leave a room
     async fn leave_room(&mut self, room: &RoomId) -> anyhow::Result<()>;
+
+    /// upload a local file and send it to the room as an attachment
+    async fn send_attachment(&mut self, _room: &RoomId, _path: &Path) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support attachments")
+    }
+
+    /// fetch the attachment of a received message, returning where it was written.
+    /// `dest` may be a directory or a file path; by default the attachment's own name is used
+    async fn download_attachment(
+        &mut self,
+        _id: &MessageId,
+        _dest: Option<&Path>,
+    ) -> anyhow::Result<PathBuf> {
+        anyhow::bail!("this backend doesn't support attachments")
+    }
+
+    /// create a new room and join it, returning its id
+    async fn create_room(&mut self, _room: &NewRoom) -> anyhow::Result<RoomId> {
+        anyhow::bail!("this backend doesn't support creating rooms")
+    }
+
+    /// invite a user to a room we're in
+    async fn invite_user(&mut self, _room: &RoomId, _user: &str) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support invites")
+    }
+
+    /// list the invites we haven't accepted or declined yet
+    async fn pending_invites(&mut self) -> anyhow::Result<Vec<RoomInvite>> {
+        anyhow::bail!("this backend doesn't support invites")
+    }
+
+    async fn accept_invite(&mut self, _room: &RoomId) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support invites")
+    }
+
+    async fn decline_invite(&mut self, _room: &RoomId) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support invites")
+    }
+
+    /// change a room's name, topic, avatar, join rule or history visibility
+    async fn update_room(&mut self, _room: &RoomId, _setting: &RoomSetting) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support room settings")
+    }
+
+    /// remove a user from a room; they can join again
+    async fn kick_user(
+        &mut self,
+        _room: &RoomId,
+        _user: &str,
+        _reason: Option<&str>,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support moderation")
+    }
+
+    /// remove a user from a room and keep them out
+    async fn ban_user(
+        &mut self,
+        _room: &RoomId,
+        _user: &str,
+        _reason: Option<&str>,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support moderation")
+    }
+
+    async fn unban_user(&mut self, _room: &RoomId, _user: &str) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support moderation")
+    }
+
+    async fn power_levels(&mut self, _room: &RoomId) -> anyhow::Result<PowerLevels> {
+        anyhow::bail!("this backend doesn't support power levels")
+    }
+
+    async fn set_power_level(
+        &mut self,
+        _room: &RoomId,
+        _user: &str,
+        _level: i64,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support power levels")
+    }
+
+    /// change the name other people see for us, everywhere or only in `room`
+    async fn set_display_name(
+        &mut self,
+        _name: &str,
+        _room: Option<&RoomId>,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support changing display names")
+    }
+
+    /// find messages containing `terms`, in one room or everywhere, best match first
+    async fn search_messages(
+        &mut self,
+        _terms: &str,
+        _room: Option<&RoomId>,
+    ) -> anyhow::Result<Vec<SearchHit>> {
+        anyhow::bail!("this backend doesn't support search")
+    }
+
+    /// message `id` with a few of the messages around it, oldest first
+    async fn message_context(
+        &mut self,
+        _room: &RoomId,
+        _id: &MessageId,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        anyhow::bail!("this backend doesn't support search")
+    }
+
+    /// the direct message room shared with `user`, created if there isn't one yet
+    async fn open_dm(&mut self, _user: &str) -> anyhow::Result<RoomId> {
+        anyhow::bail!("this backend doesn't support direct messages")
+    }
+
+    /// send automated text, such as an away reply, that other clients know not to
+    /// answer automatically; returns an id like `send_message`
+    async fn send_notice(&mut self, _room: &RoomId, _body: &str) -> anyhow::Result<Uuid> {
+        anyhow::bail!("this backend doesn't support notices")
+    }
+
+    /// whether `room` is a one-to-one conversation
+    fn is_direct(&self, _room: &RoomId) -> bool {
+        false
+    }
+
+    /// tell the room we've read everything up to and including message `id`, and
+    /// move our own fully-read marker there, so unread counts survive a restart
+    /// where the backend keeps them
+    async fn send_receipt(&mut self, _room: &RoomId, _id: &MessageId) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support read receipts")
+    }
+
+    /// tell the room we started or stopped typing; callers rate-limit this, and a
+    /// backend may drop a notice it can't deliver
+    async fn send_typing(&mut self, _room: &RoomId, _typing: bool) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support typing notices")
+    }
+
+    /// tell others whether we're around, with a status message where the backend
+    /// has room for one
+    async fn set_presence(&mut self, _presence: &Presence) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support presence")
+    }
+
+    /// who is in `room`, named as their messages show them
+    async fn list_members(&mut self, _room: &RoomId) -> anyhow::Result<Vec<String>> {
+        anyhow::bail!("this backend doesn't support member lists")
+    }
+
+    /// which messages in `room` notify us from now on
+    async fn set_notify_level(
+        &mut self,
+        _room: &RoomId,
+        _level: NotifyLevel,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("this backend doesn't support notification levels")
+    }
+
+    /// list every room the account is currently joined to
+    async fn list_rooms(&mut self) -> anyhow::Result<Vec<RoomSummary>> {
+        anyhow::bail!("this backend doesn't support listing rooms")
+    }
+
+    /// search a public room directory, our own server's unless `server` is given
+    async fn search_directory(
+        &mut self,
+        _query: Option<&str>,
+        _server: Option<&str>,
+    ) -> anyhow::Result<Vec<DirectoryRoom>> {
+        anyhow::bail!("this backend doesn't have a room directory")
+    }
+}
+
+/// Passes `event` to the app from inside one of the `ChatBackend` calls above. Those
+/// run on the app's loop, the only reader of the channel, so waiting for room in it
+/// could wait forever; when the channel is full the event is lost instead.
+pub(crate) fn report(events_tx: &mpsc::Sender<ChatEvent>, event: ChatEvent) {
+    let _ = events_tx.try_send(event);
 }
@@ -1,17 +1,47 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
 use anyhow::Context;
 use async_trait::async_trait;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::backend::{report, ChatBackend};
+use crate::protocol::{
+    mentions, Attention, ChatEvent, ChatMessage, ConnectionStatus, MessageId, NotifyLevel,
+    Presence, PresenceState, RoomId, RoomSummary, SendStatus, WireContent, WireEnvelope,
+    OLDEST_PROTOCOL_VERSION, PROTOCOL_VERSION, TYPING_TIMEOUT,
+};
 
-use crate::backend::ChatBackend;
-use crate::protocol::{ChatEvent, RoomId, WireEnvelope, PROTOCOL_VERSION};
+/// What we keep per room: there's no server to count unread messages for us
+#[derive(Debug, Default)]
+struct RoomActivity {
+    unread: u64,
+    highlights: u64,
+    notify: NotifyLevel,
+}
 
 pub struct P2PBackend {
     username: String,
     writer: OwnedWriteHalf,
     events_rx: mpsc::Receiver<ChatEvent>,
+    // our own sends are confirmed through the same channel as incoming events
+    events_tx: mpsc::Sender<ChatEvent>,
+    // the room last joined; what arrives there counts as read
+    current_room: RoomId,
+    rooms: HashMap<RoomId, RoomActivity>,
+    // when the peer's last typing notice for each room arrived
+    peer_typing: HashMap<RoomId, Instant>,
+    // the peer's name, once anything they sent has told us
+    peer: Option<String>,
+    // the newest protocol version we and the peer both speak, as their Join told
+    // the reader; until then, only what the oldest version has goes out
+    peer_version: Arc<AtomicU8>,
 }
 
 impl P2PBackend {
@@ -43,12 +73,19 @@ impl P2PBackend {
     async fn from_stream(stream: TcpStream, username: String) -> anyhow::Result<Self> {
         let (reader, writer) = stream.into_split();
         let (events_tx, events_rx) = mpsc::channel::<ChatEvent>(256);
+        let reader_events_tx = events_tx.clone();
+        let peer_version = Arc::new(AtomicU8::new(OLDEST_PROTOCOL_VERSION));
+        let reader_peer_version = peer_version.clone();
 
         tokio::spawn(async move {
+            let events_tx = reader_events_tx;
+            let peer_version = reader_peer_version;
             let mut reader = BufReader::new(reader);
             let mut line = String::new();
 
-            let _ = events_tx.send(ChatEvent::System("connected".into())).await;
+            let _ = events_tx
+                .send(ChatEvent::Connection(ConnectionStatus::Connected))
+                .await;
 
             loop {
                 // make sure we start with an empty line each iteration
@@ -81,7 +118,19 @@ impl P2PBackend {
                 let event = match serde_json::from_str::<WireEnvelope>(trimmed) {
                     Ok(env) => {
                         let incoming_version = env.version();
-                        if incoming_version == PROTOCOL_VERSION {
+                        // whatever a newer peer added is theirs to use; we carry on without it
+                        if matches!(env.content, WireContent::Unknown) {
+                            continue;
+                        }
+                        // their Join says what else they can read
+                        if let WireContent::Join {
+                            speaks: Some(speaks),
+                        } = env.content
+                        {
+                            peer_version.store(speaks.min(PROTOCOL_VERSION), Ordering::Relaxed);
+                        }
+                        if (OLDEST_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&incoming_version)
+                        {
                             env.into_chat_event()
                         } else {
                             ChatEvent::System(format!(
@@ -102,18 +151,89 @@ impl P2PBackend {
                 }
             }
 
-            // sends System message when connection is closed
-            let _ = events_tx
-                .send(ChatEvent::System("connection closed".into()))
-                .await;
+            // reports the closed connection; there's no reconnecting a single TCP peer
+            let status = ConnectionStatus::Disconnected {
+                reason: "connection closed".into(),
+            };
+            let _ = events_tx.send(ChatEvent::Connection(status)).await;
         });
 
         // returns a P2PBackend struct when the async task is successfully spawned, does not clock rest of program
-        Ok(Self {
+        let mut backend = Self {
             username,
             writer,
             events_rx,
-        })
+            events_tx,
+            current_room: RoomId::default(),
+            rooms: HashMap::from([(RoomId::default(), RoomActivity::default())]),
+            peer_typing: HashMap::new(),
+            peer: None,
+            peer_version,
+        };
+
+        // we start out in the default room; saying so tells the peer what version we speak
+        let join = WireEnvelope::join(&backend.username, &RoomId::default());
+        backend.send_envelope(&join).await?;
+        Ok(backend)
+    }
+
+    /// Writes one envelope as a line of JSON, unless it's newer than the peer reads:
+    /// a peer on an older version would fail to parse it and say so every time
+    async fn send_envelope(&mut self, envelope: &WireEnvelope) -> anyhow::Result<()> {
+        if envelope.version() > self.peer_version.load(Ordering::Relaxed) {
+            anyhow::bail!("the peer's version of rust-chat doesn't support this");
+        }
+        let json = serde_json::to_string(envelope)?;
+        self.writer.write_all(json.as_bytes()).await?;
+        self.writer.write_all(b"\n").await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Sends a reaction, or takes one back, and reports it as the peer will see it:
+    /// they don't echo it back, so this is the only way our own shows up
+    async fn send_reaction(
+        &mut self,
+        room: &RoomId,
+        id: &MessageId,
+        key: &str,
+        removed: bool,
+    ) -> anyhow::Result<()> {
+        let wire = WireEnvelope::reaction(&self.username, room, id, key, removed);
+        self.send_envelope(&wire).await?;
+
+        report(
+            &self.events_tx,
+            ChatEvent::Reaction {
+                room: room.clone(),
+                id: id.clone(),
+                user: self.username.clone(),
+                key: key.to_string(),
+                added: !removed,
+            },
+        );
+        Ok(())
+    }
+
+    /// Decides how much a received message wants our attention, and counts it as
+    /// unread unless it's in the room we're in
+    fn note_received(&mut self, message: &mut ChatMessage) {
+        let activity = self.rooms.entry(message.room.clone()).or_default();
+
+        message.attention = activity
+            .notify
+            .attention(mentions(&message.body, &self.username));
+
+        if message.room != self.current_room {
+            match message.attention {
+                Attention::None => {}
+                Attention::Notify => activity.unread += 1,
+                Attention::Highlight => {
+                    activity.unread += 1;
+                    activity.highlights += 1;
+                }
+            }
+        }
     }
 }
 
@@ -122,37 +242,167 @@ impl ChatBackend for P2PBackend {
     async fn poll_events(&mut self) -> anyhow::Result<Vec<ChatEvent>> {
         let mut events = Vec::new();
 
-        while let Ok(ev) = self.events_rx.try_recv() {
+        while let Ok(mut ev) = self.events_rx.try_recv() {
+            match &mut ev {
+                ChatEvent::Message(message) => {
+                    self.note_received(message);
+                    // the message is what they were typing
+                    self.peer_typing.remove(&message.room);
+                    self.peer = Some(message.from.clone());
+                }
+                ChatEvent::Presence { user, .. } | ChatEvent::Receipt { user, .. } => {
+                    self.peer = Some(user.clone())
+                }
+                // whoever was on the other end is gone with the connection
+                ChatEvent::Connection(ConnectionStatus::Disconnected { .. }) => {
+                    if let Some(peer) = self.peer.clone() {
+                        events.push(ev);
+                        ev = ChatEvent::Presence {
+                            user: peer,
+                            presence: Presence {
+                                state: PresenceState::Offline,
+                                message: None,
+                            },
+                        };
+                    }
+                }
+                ChatEvent::Typing { room, users } if users.is_empty() => {
+                    self.peer_typing.remove(room);
+                }
+                ChatEvent::Typing { room, users } => {
+                    self.peer_typing.insert(room.clone(), Instant::now());
+                    self.peer = users.first().cloned();
+                }
+                _ => {}
+            }
             events.push(ev);
         }
 
+        // there's no server to time typing out, and the peer may go quiet mid-sentence
+        let expired: Vec<RoomId> = self
+            .peer_typing
+            .iter()
+            .filter(|(_, since)| since.elapsed() >= TYPING_TIMEOUT)
+            .map(|(room, _)| room.clone())
+            .collect();
+        for room in expired {
+            self.peer_typing.remove(&room);
+            events.push(ChatEvent::Typing {
+                room,
+                users: Vec::new(),
+            });
+        }
+
         Ok(events)
     }
 
+    fn own_user(&self) -> String {
+        self.username.clone()
+    }
+
     async fn join_room(&mut self, room: &RoomId) -> anyhow::Result<()> {
         let wire = WireEnvelope::join(&self.username, room);
-        let json = serde_json::to_string(&wire)?;
-        self.writer.write_all(json.as_bytes()).await?;
-        self.writer.write_all(b"\n").await?;
-        self.writer.flush().await?;
+        self.send_envelope(&wire).await?;
+
+        let activity = self.rooms.entry(room.clone()).or_default();
+        activity.unread = 0;
+        activity.highlights = 0;
+        self.current_room = room.clone();
         Ok(())
     }
 
     async fn leave_room(&mut self, room: &RoomId) -> anyhow::Result<()> {
         let wire = WireEnvelope::leave(&self.username, room);
-        let json = serde_json::to_string(&wire)?;
-        self.writer.write_all(json.as_bytes()).await?;
-        self.writer.write_all(b"\n").await?;
-        self.writer.flush().await?;
+        self.send_envelope(&wire).await?;
+
+        self.rooms.remove(room);
         Ok(())
     }
 
-    async fn send_message(&mut self, room: &RoomId, body: &str) -> anyhow::Result<()> {
+    async fn send_message(&mut self, room: &RoomId, body: &str) -> anyhow::Result<Uuid> {
         let wire = WireEnvelope::chat(&self.username, room, body);
-        let json = serde_json::to_string(&wire)?;
-        self.writer.write_all(json.as_bytes()).await?;
-        self.writer.write_all(b"\n").await?;
-        self.writer.flush().await?;
+        self.send_envelope(&wire).await?;
+
+        // once it's written the peer has it; there's no separate server id or clock
+        let status = SendStatus::Sent {
+            remote_id: wire.id.into(),
+            ts: Some(wire.ts),
+        };
+        report(
+            &self.events_tx,
+            ChatEvent::SendStatus {
+                id: wire.id,
+                room: room.clone(),
+                status,
+            },
+        );
+
+        Ok(wire.id)
+    }
+
+    async fn send_typing(&mut self, room: &RoomId, typing: bool) -> anyhow::Result<()> {
+        let wire = WireEnvelope::typing(&self.username, room, typing);
+        self.send_envelope(&wire).await
+    }
+
+    async fn react(&mut self, room: &RoomId, id: &MessageId, key: &str) -> anyhow::Result<()> {
+        self.send_reaction(room, id, key, false).await
+    }
+
+    async fn unreact(&mut self, room: &RoomId, id: &MessageId, key: &str) -> anyhow::Result<()> {
+        self.send_reaction(room, id, key, true).await
+    }
+
+    async fn send_receipt(&mut self, room: &RoomId, id: &MessageId) -> anyhow::Result<()> {
+        let wire = WireEnvelope::receipt(&self.username, room, id);
+        self.send_envelope(&wire).await
+    }
+
+    async fn set_presence(&mut self, presence: &Presence) -> anyhow::Result<()> {
+        // with a single peer, telling the connection is telling everyone we share a room with
+        let wire = WireEnvelope::presence(&self.username, presence);
+        self.send_envelope(&wire).await
+    }
+
+    async fn list_members(&mut self, _room: &RoomId) -> anyhow::Result<Vec<String>> {
+        let mut members = vec![self.username.clone()];
+        members.extend(self.peer.clone());
+        Ok(members)
+    }
+
+    async fn set_notify_level(&mut self, room: &RoomId, level: NotifyLevel) -> anyhow::Result<()> {
+        self.rooms.entry(room.clone()).or_default().notify = level;
+        Ok(())
+    }
+
+    async fn list_rooms(&mut self) -> anyhow::Result<Vec<RoomSummary>> {
+        let mut rooms: Vec<RoomSummary> = self
+            .rooms
+            .iter()
+            .map(|(room, activity)| RoomSummary {
+                room: room.clone(),
+                name: room.to_string(),
+                alias: None,
+                // a TCP session is only ever us and the one peer
+                members: 2,
+                unread: activity.unread,
+                highlights: activity.highlights,
+                notify: activity.notify,
+                direct: false,
+            })
+            .collect();
+
+        rooms.sort_by_key(|room| room.name.to_lowercase());
+
+        Ok(rooms)
+    }
+
+    async fn set_display_name(&mut self, name: &str, room: Option<&RoomId>) -> anyhow::Result<()> {
+        // every envelope carries the sender's name, so there's nothing to announce
+        if room.is_some() {
+            anyhow::bail!("per-room names aren't supported over TCP");
+        }
+        self.username = name.to_string();
         Ok(())
     }
 }
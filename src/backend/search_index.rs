@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+
+use crate::protocol::{ChatMessage, MessageId, RoomId, SearchHit};
+
+/// How many messages the index keeps before dropping the oldest
+const MAX_INDEXED: usize = 20_000;
+
+/// Messages kept in memory so `/search` can find them where the server can't,
+/// e.g. in end-to-end encrypted Matrix rooms
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    messages: VecDeque<ChatMessage>,
+}
+
+impl SearchIndex {
+    pub fn insert(&mut self, message: ChatMessage) {
+        if self.messages.len() == MAX_INDEXED {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+
+    pub fn edit(&mut self, id: &MessageId, body: &str) {
+        if let Some(message) = self.messages.iter_mut().find(|m| m.id == *id) {
+            message.body = body.to_string();
+        }
+    }
+
+    pub fn remove(&mut self, id: &MessageId) {
+        self.messages.retain(|m| m.id != *id);
+    }
+
+    /// up to `limit` messages containing every word of `terms`, ignoring case, ranked
+    /// by how often the words occur and then by recency
+    pub fn search(&self, terms: &str, room: Option<&RoomId>, limit: usize) -> Vec<SearchHit> {
+        let terms: Vec<String> = terms.split_whitespace().map(str::to_lowercase).collect();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let mut hits: Vec<(usize, &ChatMessage)> = self
+            .messages
+            .iter()
+            .filter(|message| room.is_none_or(|room| message.room == *room))
+            .filter_map(|message| {
+                let body = message.body.to_lowercase();
+                let counts: Vec<usize> = terms
+                    .iter()
+                    .map(|term| body.matches(term.as_str()).count())
+                    .collect();
+                (!counts.contains(&0)).then(|| (counts.iter().sum(), message))
+            })
+            .collect();
+
+        hits.sort_by(|(a_count, a), (b_count, b)| b_count.cmp(a_count).then(b.ts.cmp(&a.ts)));
+
+        hits.into_iter()
+            .take(limit)
+            .map(|(count, message)| SearchHit {
+                message: message.clone(),
+                rank: Some(count as f64),
+            })
+            .collect()
+    }
+}
+
+/// Puts two lists of hits, each ranked on its own scale, into one list ranked best
+/// first. Each list's scores are scaled so its best is 1, then the two are merged,
+/// the newer message first where scores tie; hits without a score come last.
+pub fn merge_ranked(first: Vec<SearchHit>, second: Vec<SearchHit>, limit: usize) -> Vec<SearchHit> {
+    let mut hits: Vec<SearchHit> = [first, second]
+        .into_iter()
+        .flat_map(|hits| {
+            let best = hits.iter().filter_map(|hit| hit.rank).fold(0.0, f64::max);
+            hits.into_iter().map(move |hit| SearchHit {
+                rank: hit.rank.filter(|_| best > 0.0).map(|rank| rank / best),
+                ..hit
+            })
+        })
+        .collect();
+
+    hits.sort_by(|a, b| {
+        let score = |hit: &SearchHit| hit.rank.unwrap_or(-1.0);
+        score(b)
+            .total_cmp(&score(a))
+            .then(b.message.ts.cmp(&a.message.ts))
+    });
+    hits.truncate(limit);
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Attention, MessageKind, MessageOrigin};
+    use chrono::{Duration, Utc};
+
+    fn message(id: &str, room: &str, body: &str, age_secs: i64) -> ChatMessage {
+        ChatMessage {
+            id: MessageId::new(id),
+            ts: Utc::now() - Duration::seconds(age_secs),
+            from: "alice".to_string(),
+            room: RoomId::new(room),
+            body: body.to_string(),
+            formatted: None,
+            kind: MessageKind::Text,
+            attachment: None,
+            origin: MessageOrigin::default(),
+            in_reply_to: None,
+            thread: None,
+            attention: Attention::None,
+        }
+    }
+
+    fn ids(hits: &[SearchHit]) -> Vec<&str> {
+        hits.iter().map(|hit| hit.message.id.as_str()).collect()
+    }
+
+    #[test]
+    fn every_term_must_match_and_more_matches_rank_higher() {
+        let mut index = SearchIndex::default();
+        index.insert(message("$old", "!a", "Deploy the release", 60));
+        index.insert(message("$new", "!a", "release notes for the release", 30));
+        index.insert(message("$other", "!a", "notes only", 10));
+        index.insert(message("$newest", "!a", "the release is out", 0));
+
+        let hits = index.search("RELEASE the", None, 10);
+        assert_eq!(ids(&hits), ["$new", "$newest", "$old"]);
+
+        assert_eq!(ids(&index.search("release", None, 1)), ["$new"]);
+        assert!(index.search("  ", None, 10).is_empty());
+    }
+
+    #[test]
+    fn search_follows_room_filter_edits_and_deletions() {
+        let mut index = SearchIndex::default();
+        index.insert(message("$a", "!a", "lunch at noon", 10));
+        index.insert(message("$b", "!b", "lunch at one", 0));
+
+        assert_eq!(
+            ids(&index.search("lunch", Some(&RoomId::new("!a")), 10)),
+            ["$a"]
+        );
+
+        index.edit(&MessageId::new("$a"), "dinner at seven");
+        index.remove(&MessageId::new("$b"));
+        assert!(index.search("lunch", None, 10).is_empty());
+        assert_eq!(ids(&index.search("dinner", None, 10)), ["$a"]);
+    }
+
+    #[test]
+    fn merged_hits_are_ranked_against_their_own_best() {
+        let hit = |id: &str, age_secs: i64, rank: Option<f64>| SearchHit {
+            message: message(id, "!a", "x", age_secs),
+            rank,
+        };
+        let server = vec![
+            hit("$server_best", 30, Some(40.0)),
+            hit("$server_half", 0, Some(20.0)),
+            hit("$server_unranked", 0, None),
+        ];
+        let local = vec![
+            hit("$local_best", 10, Some(2.0)),
+            hit("$local_half", 20, Some(1.0)),
+        ];
+
+        let merged = merge_ranked(server, local, 4);
+        assert_eq!(
+            ids(&merged),
+            ["$local_best", "$server_best", "$server_half", "$local_half"]
+        );
+        assert_eq!(merged[0].rank, Some(1.0));
+        assert_eq!(merged[3].rank, Some(0.5));
+    }
+}
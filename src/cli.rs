@@ -1,13 +1,38 @@
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 #[derive(Parser, Debug)]
 #[command(name = "rust-chat")]
 #[command(about = "Phase 1: TCP chat with a backend abstraction")]
 pub struct Cli {
+    /// How to get your attention for mentions, and for new messages in
+    /// other rooms that notify you
+    #[arg(long, global = true, value_enum, default_value_t = Alert::Off)]
+    pub alert: Alert,
+
+    /// Minutes without typing before you're shown as away; 0 never
+    #[arg(long, global = true, default_value_t = 10)]
+    pub away_after: u64,
+
+    /// While you're away, answer direct messages with a notice saying so
+    #[arg(long, global = true)]
+    pub auto_reply: bool,
+
     #[command(subcommand)]
     pub command: Command,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alert {
+    /// Only highlight mentions on screen
+    Off,
+    /// Ring the terminal bell
+    Bell,
+    /// Send a desktop notification through the terminal (OSC 9)
+    Osc9,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Command {
     /// Listen for a single incoming TCP connection
@@ -43,16 +68,80 @@ pub enum Command {
         homeserver: String,
 
         /// Matrix user ID or localpart to log in as
-        #[arg(short, long)]
-        user_id: String,
+        #[arg(short, long, required_unless_present_any = ["guest", "access_token", "sso"])]
+        user_id: Option<String>,
 
         /// Account password
+        #[arg(short, long, required_unless_present_any = ["guest", "access_token", "sso"])]
+        password: Option<String>,
+
+        /// Log in with the access token of an existing session instead of a password
+        #[arg(long, value_name = "TOKEN", conflicts_with_all = ["user_id", "password", "sso"])]
+        access_token: Option<String>,
+
+        /// Log in through the homeserver's single sign-on page in a browser
+        #[arg(long, conflicts_with_all = ["user_id", "password"])]
+        sso: bool,
+
+        /// Watch public rooms without an account, as a read-only guest
+        /// (only on homeservers that allow guest access)
+        #[arg(long, conflicts_with_all = ["user_id", "password", "access_token", "sso"])]
+        guest: bool,
+
+        #[command(flatten)]
+        connection: MatrixConnection,
+
+        /// Automatically accept room invites sent by this user ID
+        /// (may be given more than once)
+        #[arg(long = "auto-accept-from", value_name = "USER_ID")]
+        auto_accept_from: Vec<String>,
+    },
+
+    /// Create an account on a Matrix homeserver with open registration, then connect
+    Register {
+        /// Matrix homeserver name (e.g. matrix.org)
+        #[arg(short = 'H', long)]
+        homeserver: String,
+
+        /// Localpart of the new account, e.g. `alice` for @alice:example.org
+        #[arg(short, long)]
+        username: String,
+
+        /// Password for the new account
         #[arg(short, long)]
         password: String,
 
-        /// Connect over plain HTTP instead of HTTPS (for local test
-        /// homeservers, e.g. Synapse or Conduit run without a reverse proxy)
-        #[arg(long)]
-        insecure: bool,
+        #[command(flatten)]
+        connection: MatrixConnection,
     },
 }
+
+/// How to reach a Matrix homeserver, shared by `matrix` and `register`
+#[derive(Args, Debug)]
+pub struct MatrixConnection {
+    /// Connect over plain HTTP instead of HTTPS (for local test
+    /// homeservers, e.g. Synapse or Conduit run without a reverse proxy)
+    #[arg(long)]
+    pub insecure: bool,
+
+    /// Connect to this URL (e.g. https://matrix.example.org) instead of
+    /// looking the homeserver up through .well-known
+    #[arg(long, value_name = "URL", conflicts_with = "insecure")]
+    pub homeserver_url: Option<String>,
+
+    /// Also trust the CA certificates in this PEM file
+    #[arg(long, value_name = "PEM_FILE")]
+    pub ca_cert: Option<PathBuf>,
+
+    /// Present this client certificate (PEM, which may also hold the key)
+    #[arg(long, value_name = "PEM_FILE")]
+    pub client_cert: Option<PathBuf>,
+
+    /// Private key for --client-cert, if it's in a separate PEM file
+    #[arg(long, value_name = "PEM_FILE", requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+
+    /// Send all traffic through this proxy (http://, https:// or socks5:// URL)
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+}
@@ -1,10 +1,26 @@
 use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-pub const PROTOCOL_VERSION: u8 = 2;
+mod rich;
+
+pub use rich::{Block, Inline, RichText, MAX_FORMATTED_LEN, MAX_NESTING};
+
+/// The newest version of the TCP wire protocol we speak. Version 3 added typing
+/// notices, reactions, read receipts and presence.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// The oldest version we still talk to. What a peer on it can read goes out under
+/// this version; anything newer waits until the peer's Join says it speaks more.
+pub const OLDEST_PROTOCOL_VERSION: u8 = 2;
+
+/// How long someone shows as typing after their last typing notice, unless they
+/// stop sooner; senders repeat the notice well within this while they keep typing
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(transparent)]
@@ -32,14 +48,637 @@ impl fmt::Display for RoomId {
     }
 }
 
+/// A message as its backend identifies it: the envelope UUID over TCP, the event id on Matrix
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MessageId(String);
+
+impl MessageId {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<Uuid> for MessageId {
+    fn from(id: Uuid) -> Self {
+        Self(id.to_string())
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// How a message body is meant to be read, mirroring Matrix's text/notice/emote msgtypes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MessageKind {
+    #[default]
+    Text,
+    /// automated output, e.g. from a bot
+    Notice,
+    /// an action, e.g. `/me waves` has the body "waves"
+    Emote,
+}
+
+/// How the body of a chat message over TCP is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyFormat {
+    #[default]
+    Plain,
+    Markdown,
+}
+
+impl BodyFormat {
+    fn is_plain(&self) -> bool {
+        *self == BodyFormat::Plain
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachmentKind {
+    Image,
+    Audio,
+    Video,
+    File,
+}
+
+impl fmt::Display for AttachmentKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AttachmentKind::Image => "image",
+            AttachmentKind::Audio => "audio",
+            AttachmentKind::Video => "video",
+            AttachmentKind::File => "file",
+        })
+    }
+}
+
+/// Describes a file attached to a message, not the file contents themselves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub kind: AttachmentKind,
+    pub name: String,
+    pub size: Option<u64>,
+    pub mime: Option<String>,
+}
+
+impl fmt::Display for Attachment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} '{}'", self.kind, self.name)?;
+
+        let details: Vec<String> = [self.size.map(human_size), self.mime.clone()]
+            .into_iter()
+            .flatten()
+            .collect();
+
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Formats a byte count using binary units, e.g. `1536` -> `1.5 KiB`
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+
+    format!("{:.1} {}", size, unit)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MembershipChange {
+    Joined,
+    Left,
+    Invited {
+        by: String,
+    },
+    Kicked {
+        by: String,
+        reason: Option<String>,
+    },
+    Banned {
+        by: String,
+        reason: Option<String>,
+    },
+    Unbanned {
+        by: String,
+    },
+    InviteRejected,
+    InviteRevoked {
+        by: String,
+    },
+    /// the user changed their display name; `user` is the old label
+    Renamed {
+        to: String,
+    },
+}
+
+/// How to show a user: their display name, with the full user id added when
+/// someone else in the room shares that name, or just the id when there's no name
+pub fn user_label(display_name: Option<&str>, user_id: &str, ambiguous: bool) -> String {
+    match display_name {
+        Some(name) if ambiguous => format!("{} ({})", name, user_id),
+        Some(name) if !name.trim().is_empty() => name.to_string(),
+        _ => user_id.to_string(),
+    }
+}
+
+/// Whether `body` mentions `name` as a whole word, ignoring case and an `@` in front;
+/// for backends without server-side push rules
+pub fn mentions(body: &str, name: &str) -> bool {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        return false;
+    }
+
+    let body = body.to_lowercase();
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+
+    body.match_indices(&name).any(|(start, found)| {
+        !is_word(body[..start].chars().next_back())
+            && !is_word(body[start + found.len()..].chars().next())
+    })
+}
+
+/// Which messages in a room should notify us
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NotifyLevel {
+    #[default]
+    All,
+    /// only messages that mention us
+    Mentions,
+    Muted,
+}
+
+impl NotifyLevel {
+    /// what a received message in a room at this level asks of us
+    pub fn attention(self, mentioned: bool) -> Attention {
+        match self {
+            NotifyLevel::Muted => Attention::None,
+            _ if mentioned => Attention::Highlight,
+            NotifyLevel::All => Attention::Notify,
+            NotifyLevel::Mentions => Attention::None,
+        }
+    }
+}
+
+impl fmt::Display for NotifyLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotifyLevel::All => write!(f, "all messages"),
+            NotifyLevel::Mentions => write!(f, "mentions only"),
+            NotifyLevel::Muted => write!(f, "muted"),
+        }
+    }
+}
+
+/// Whether someone is around to read what's sent to them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    #[default]
+    Online,
+    /// connected, but idle or said they're away
+    Away,
+    Offline,
+}
+
+impl fmt::Display for PresenceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresenceState::Online => write!(f, "online"),
+            PresenceState::Away => write!(f, "away"),
+            PresenceState::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+/// Someone's presence, with the status message they gave it
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Presence {
+    pub state: PresenceState,
+    pub message: Option<String>,
+}
+
+impl Presence {
+    pub fn away(message: Option<String>) -> Self {
+        Self {
+            state: PresenceState::Away,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "{}: {}", self.state, message),
+            None => write!(f, "{}", self.state),
+        }
+    }
+}
+
+/// How much a received message wants our attention, after the room's notification level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Attention {
+    /// our own messages, and ones the room's level keeps quiet
+    #[default]
+    None,
+    Notify,
+    /// mentions us
+    Highlight,
+}
+
+/// Who sent a message, down to the device where the backend can tell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageOrigin {
+    /// another user
+    Remote { device: Option<String> },
+    /// our own account, sent from another device or client (e.g. a phone)
+    OwnDevice { device: Option<String> },
+    /// the local echo of a message this session sent
+    Local,
+}
+
+impl Default for MessageOrigin {
+    fn default() -> Self {
+        Self::Remote { device: None }
+    }
+}
+
+impl MessageOrigin {
+    /// sent by our own account, from this session or another
+    pub fn is_own(&self) -> bool {
+        !matches!(self, Self::Remote { .. })
+    }
+}
+
+/// What `/create` asks the backend to set up
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NewRoom {
+    pub name: String,
+    /// listed in the server's room directory and joinable by anyone
+    pub public: bool,
+    pub encrypted: bool,
+}
+
+/// Who may join a room without being invited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinRule {
+    Public,
+    Invite,
+    /// anyone may ask to join, and members let them in
+    Knock,
+}
+
+/// Who can read the messages sent before they joined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryVisibility {
+    /// anyone, even without joining
+    WorldReadable,
+    /// members, including what was sent before they joined
+    Shared,
+    /// members, from the point they were invited
+    Invited,
+    /// members, from the point they joined
+    Joined,
+}
+
+/// A room setting changed with `/room`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomSetting {
+    Name(String),
+    Topic(String),
+    /// an image file to upload as the room's avatar
+    Avatar(PathBuf),
+    JoinRule(JoinRule),
+    HistoryVisibility(HistoryVisibility),
+}
+
+/// A member's power level in a room. From Matrix room version 12 on, room creators
+/// outrank any numeric level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerLevel {
+    Level(i64),
+    Creator,
+}
+
+impl fmt::Display for PowerLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerLevel::Level(level) => write!(f, "{}", level),
+            PowerLevel::Creator => f.write_str("creator"),
+        }
+    }
+}
+
+/// Who holds which power level in a room, and the level each action needs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PowerLevels {
+    /// our own level
+    pub own: PowerLevel,
+    /// members whose level differs from `users_default`, highest first
+    pub users: Vec<(String, PowerLevel)>,
+    pub users_default: i64,
+    pub events_default: i64,
+    pub state_default: i64,
+    pub invite: i64,
+    pub kick: i64,
+    pub ban: i64,
+    pub redact: i64,
+}
+
+impl fmt::Display for PowerLevels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "you: {}", self.own)?;
+
+        for (user, level) in &self.users {
+            writeln!(f, "{}: {}", user, level)?;
+        }
+        writeln!(f, "everyone else: {}", self.users_default)?;
+
+        write!(
+            f,
+            "needed to send messages: {}, change room settings: {}, invite: {}, kick: {}, ban: {}, delete others' messages: {}",
+            self.events_default, self.state_default, self.invite, self.kick, self.ban, self.redact
+        )
+    }
+}
+
+/// An invitation to a room we haven't joined yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomInvite {
+    pub room: RoomId,
+    pub name: Option<String>,
+    pub inviter: Option<String>,
+}
+
+impl fmt::Display for RoomInvite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", name, self.room)?,
+            None => write!(f, "{}", self.room)?,
+        }
+
+        if let Some(inviter) = &self.inviter {
+            write!(f, " from {}", inviter)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A room the account has joined, as listed by `/rooms`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomSummary {
+    pub room: RoomId,
+    pub name: String,
+    pub alias: Option<String>,
+    pub members: u64,
+    /// messages that notified us since we last read the room
+    pub unread: u64,
+    /// how many of those mention us
+    pub highlights: u64,
+    pub notify: NotifyLevel,
+    /// a direct message room, which is named after the person it's with
+    pub direct: bool,
+}
+
+impl fmt::Display for RoomSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.name)?;
+
+        match (&self.alias, self.direct) {
+            (Some(alias), _) => write!(f, "({}, {})", alias, self.room)?,
+            (None, true) => write!(f, "(direct message, {})", self.room)?,
+            (None, false) => write!(f, "({})", self.room)?,
+        }
+
+        write!(f, " - {} members", self.members)?;
+
+        if self.unread > 0 {
+            write!(f, ", {} unread", self.unread)?;
+        }
+
+        if self.highlights > 0 {
+            write!(f, ", {} mentioning you", self.highlights)?;
+        }
+
+        if self.notify != NotifyLevel::All {
+            write!(f, " [{}]", self.notify)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A room listed in a server's public room directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryRoom {
+    pub room: RoomId,
+    pub name: Option<String>,
+    pub alias: Option<String>,
+    pub topic: Option<String>,
+    pub members: u64,
+}
+
+impl DirectoryRoom {
+    /// What to pass to `join_room`; aliases are preferred since they're what people share
+    pub fn address(&self) -> RoomId {
+        match &self.alias {
+            Some(alias) => RoomId::new(alias.clone()),
+            None => self.room.clone(),
+        }
+    }
+}
+
+impl fmt::Display for DirectoryRoom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", name, self.address())?,
+            None => write!(f, "{}", self.address())?,
+        }
+
+        write!(f, " - {} members", self.members)?;
+
+        if let Some(topic) = &self.topic {
+            write!(f, ": {}", topic)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The state of a backend's link to its server, reported whenever it changes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connected,
+    /// a recoverable failure; the backend tries again by itself after `retry_in`
+    Reconnecting {
+        error: String,
+        attempt: u32,
+        retry_in: Duration,
+    },
+    /// the backend gave up and won't deliver any more events
+    Disconnected {
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionStatus::Connected => f.write_str("connected"),
+            ConnectionStatus::Reconnecting {
+                error,
+                attempt,
+                retry_in,
+            } => write!(
+                f,
+                "connection problem ({}), retrying in {}s (attempt {})",
+                error,
+                retry_in.as_secs(),
+                attempt
+            ),
+            ConnectionStatus::Disconnected { reason } => write!(f, "disconnected: {}", reason),
+        }
+    }
+}
+
+/// Where one of our outgoing messages is on its way to the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendStatus {
+    /// queued, or waiting to be retried
+    Pending,
+    /// accepted by the server under `remote_id`; `ts` is the server's timestamp
+    /// once the backend has seen it, which can arrive in a later update
+    Sent {
+        remote_id: MessageId,
+        ts: Option<DateTime<Utc>>,
+    },
+    /// gave up; the backend keeps the message so it can be retried
+    Failed { error: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    pub id: MessageId,
+    pub ts: DateTime<Utc>,
+    pub from: String,
+    pub room: RoomId,
+    pub body: String,
+    /// `body` with its formatting, if the sender used any; `body` is the fallback
+    pub formatted: Option<RichText>,
+    pub kind: MessageKind,
+    pub attachment: Option<Attachment>,
+    pub origin: MessageOrigin,
+    /// the message this one replies to
+    pub in_reply_to: Option<MessageId>,
+    /// the root of the thread this message was posted in
+    pub thread: Option<MessageId>,
+    pub attention: Attention,
+}
+
+/// A `/search` match; `rank` is the backend's relevance score, higher is better
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub message: ChatMessage,
+    pub rank: Option<f64>,
+}
+
 #[derive(Debug, Clone)]
 pub enum ChatEvent {
-    Message {
-        id: Uuid,
+    Message(ChatMessage),
+
+    Membership {
+        ts: DateTime<Utc>,
+        room: RoomId,
+        user: String,
+        change: MembershipChange,
+    },
+
+    /// the sender replaced the text of message `id`
+    Edited {
         ts: DateTime<Utc>,
-        from: String,
         room: RoomId,
+        id: MessageId,
+        from: String,
+        /// whether the edit is ours, as for a message
+        origin: MessageOrigin,
         body: String,
+        formatted: Option<RichText>,
+    },
+
+    /// message `id` was deleted, by its sender or a moderator
+    Redacted {
+        ts: DateTime<Utc>,
+        room: RoomId,
+        id: MessageId,
+        by: String,
+        reason: Option<String>,
+    },
+
+    /// someone invited us to a room; accept or decline it through the backend
+    Invite(RoomInvite),
+
+    Connection(ConnectionStatus),
+
+    /// progress of a message we sent, `id` being what `send_message` returned
+    SendStatus {
+        id: Uuid,
+        room: RoomId,
+        status: SendStatus,
+    },
+
+    /// `user` added or took back reaction `key`, usually an emoji, on message `id`
+    Reaction {
+        room: RoomId,
+        id: MessageId,
+        user: String,
+        key: String,
+        added: bool,
+    },
+
+    /// `user` has read everything in `room` up to and including message `id`
+    Receipt {
+        room: RoomId,
+        user: String,
+        id: MessageId,
+    },
+
+    /// `user`, named as their messages show them, is now online, away or gone
+    Presence {
+        user: String,
+        presence: Presence,
+    },
+
+    /// everyone other than us typing in `room` right now, replacing whatever the last
+    /// `Typing` for the room said; an empty list means nobody is
+    Typing {
+        room: RoomId,
+        users: Vec<String>,
     },
 
     System(String),
@@ -59,10 +698,60 @@ pub struct WireEnvelope {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum WireContent {
-    Chat { body: String },
-    Join,
+    Chat {
+        body: String,
+        /// plain unless the sender used Markdown; older peers leave it out
+        #[serde(default, skip_serializing_if = "BodyFormat::is_plain")]
+        format: BodyFormat,
+    },
+    Join {
+        /// the newest protocol version the sender speaks; peers on version 2 leave
+        /// it out, and skip over it in ours
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        speaks: Option<u8>,
+    },
     Leave,
-    System { text: String },
+    /// the sender started or stopped typing in the room
+    Typing {
+        typing: bool,
+    },
+    /// the sender reacted to the envelope with id `target`, or took that reaction back
+    Reaction {
+        target: MessageId,
+        key: String,
+        #[serde(default)]
+        removed: bool,
+    },
+    /// the sender has read the room up to and including `message`
+    Receipt {
+        message: MessageId,
+    },
+    /// the sender is now online, away or about to go offline
+    Presence {
+        state: PresenceState,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    System {
+        text: String,
+    },
+    /// a type from a peer newer than us. Peers that only know version 2 fail on
+    /// any type they don't know, so new types need a new protocol version
+    #[serde(other, skip_serializing)]
+    Unknown,
+}
+
+impl WireContent {
+    /// the oldest protocol version that has this type
+    fn since(&self) -> u8 {
+        match self {
+            Self::Typing { .. }
+            | Self::Reaction { .. }
+            | Self::Receipt { .. }
+            | Self::Presence { .. } => 3,
+            _ => OLDEST_PROTOCOL_VERSION,
+        }
+    }
 }
 
 impl WireEnvelope {
@@ -81,19 +770,29 @@ impl WireEnvelope {
         } = self;
 
         match content {
-            WireContent::Chat { body } => {
+            WireContent::Chat { body, format } => {
                 let Some(room) = room.as_ref() else {
                     return ChatEvent::System("missing <room> for Chat".to_string());
                 };
-                ChatEvent::Message {
-                    id,
+                ChatEvent::Message(ChatMessage {
+                    id: id.into(),
                     ts,
                     from,
                     room: room.clone(),
+                    formatted: match format {
+                        BodyFormat::Markdown => RichText::from_markdown(&body),
+                        BodyFormat::Plain => None,
+                    },
                     body,
-                }
+                    kind: MessageKind::Text,
+                    attachment: None,
+                    origin: MessageOrigin::default(),
+                    in_reply_to: None,
+                    thread: None,
+                    attention: Attention::default(),
+                })
             }
-            WireContent::Join => {
+            WireContent::Join { .. } => {
                 let Some(room) = room.as_ref() else {
                     return ChatEvent::System("missing <room> for Join".to_string());
                 };
@@ -105,43 +804,136 @@ impl WireEnvelope {
                 };
                 ChatEvent::System(format!("{} left {}", from, room))
             }
+            WireContent::Typing { typing } => {
+                let Some(room) = room else {
+                    return ChatEvent::System("missing <room> for Typing".to_string());
+                };
+                let users = if typing { vec![from] } else { Vec::new() };
+                ChatEvent::Typing { room, users }
+            }
+            WireContent::Reaction {
+                target,
+                key,
+                removed,
+            } => {
+                let Some(room) = room else {
+                    return ChatEvent::System("missing <room> for Reaction".to_string());
+                };
+                ChatEvent::Reaction {
+                    room,
+                    id: target,
+                    user: from,
+                    key,
+                    added: !removed,
+                }
+            }
+            WireContent::Receipt { message } => {
+                let Some(room) = room else {
+                    return ChatEvent::System("missing <room> for Receipt".to_string());
+                };
+                ChatEvent::Receipt {
+                    room,
+                    user: from,
+                    id: message,
+                }
+            }
+            WireContent::Presence { state, message } => ChatEvent::Presence {
+                user: from,
+                presence: Presence { state, message },
+            },
             WireContent::System { text } => ChatEvent::System(text),
+            WireContent::Unknown => {
+                ChatEvent::System(format!("{} sent something this version can't show", from))
+            }
         }
     }
 
-    pub fn chat(from: &str, room: &RoomId, body: &str) -> Self {
+    /// stamps `content` with the oldest version that has it, so that whatever a
+    /// peer on that version can read goes out in a form it accepts
+    fn new(from: &str, room: Option<&RoomId>, content: WireContent) -> Self {
         Self {
-            v: PROTOCOL_VERSION,
+            v: content.since(),
             id: Uuid::new_v4(),
             ts: Utc::now(),
             from: from.to_string(),
-            room: Some(room.clone()),
-            content: WireContent::Chat {
+            room: room.cloned(),
+            content,
+        }
+    }
+
+    /// a chat message, marked as Markdown if `body` uses any
+    pub fn chat(from: &str, room: &RoomId, body: &str) -> Self {
+        let format = match RichText::from_markdown(body) {
+            Some(_) => BodyFormat::Markdown,
+            None => BodyFormat::Plain,
+        };
+
+        Self::new(
+            from,
+            Some(room),
+            WireContent::Chat {
                 body: body.to_string(),
+                format,
             },
-        }
+        )
     }
 
     pub fn join(from: &str, room: &RoomId) -> Self {
-        Self {
-            v: PROTOCOL_VERSION,
-            id: Uuid::new_v4(),
-            ts: Utc::now(),
-            from: from.to_string(),
-            room: Some(room.clone()),
-            content: WireContent::Join,
-        }
+        Self::new(
+            from,
+            Some(room),
+            WireContent::Join {
+                speaks: Some(PROTOCOL_VERSION),
+            },
+        )
     }
 
     pub fn leave(from: &str, room: &RoomId) -> Self {
-        Self {
-            v: PROTOCOL_VERSION,
-            id: Uuid::new_v4(),
-            ts: Utc::now(),
-            from: from.to_string(),
-            room: Some(room.clone()),
-            content: WireContent::Leave,
-        }
+        Self::new(from, Some(room), WireContent::Leave)
+    }
+
+    pub fn typing(from: &str, room: &RoomId, typing: bool) -> Self {
+        Self::new(from, Some(room), WireContent::Typing { typing })
+    }
+
+    pub fn reaction(
+        from: &str,
+        room: &RoomId,
+        target: &MessageId,
+        key: &str,
+        removed: bool,
+    ) -> Self {
+        Self::new(
+            from,
+            Some(room),
+            WireContent::Reaction {
+                target: target.clone(),
+                key: key.to_string(),
+                removed,
+            },
+        )
+    }
+
+    pub fn receipt(from: &str, room: &RoomId, message: &MessageId) -> Self {
+        Self::new(
+            from,
+            Some(room),
+            WireContent::Receipt {
+                message: message.clone(),
+            },
+        )
+    }
+
+    /// presence is about the sender, not a room, so it goes out without one
+    pub fn presence(from: &str, presence: &Presence) -> Self {
+        Self::new(
+            from,
+            None,
+            WireContent::Presence {
+                state: presence.state,
+                message: presence.message.clone(),
+            },
+        )
     }
 }
 
@@ -156,14 +948,14 @@ mod tests {
         let event = envelope.clone().into_chat_event();
 
         match event {
-            ChatEvent::Message {
+            ChatEvent::Message(ChatMessage {
                 id,
                 from,
                 room,
                 body,
                 ..
-            } => {
-                assert_eq!(id, envelope.id);
+            }) => {
+                assert_eq!(id, MessageId::from(envelope.id));
                 assert_eq!(from, "alice");
                 assert_eq!(room, RoomId::new("general"));
                 assert_eq!(body, "hello");
@@ -235,6 +1027,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn typing_names_the_sender_until_they_stop() {
+        let room = RoomId::new("general");
+
+        match WireEnvelope::typing("bob", &room, true).into_chat_event() {
+            ChatEvent::Typing { room, users } => {
+                assert_eq!(room, RoomId::new("general"));
+                assert_eq!(users, ["bob"]);
+            }
+            other => panic!("expected ChatEvent::Typing, got {:?}", other),
+        }
+
+        match WireEnvelope::typing("bob", &room, false).into_chat_event() {
+            ChatEvent::Typing { users, .. } => assert!(users.is_empty()),
+            other => panic!("expected ChatEvent::Typing, got {:?}", other),
+        }
+    }
+
     #[test]
     fn system_content_passes_text_through_regardless_of_room() {
         let envelope = WireEnvelope {
@@ -260,9 +1070,37 @@ mod tests {
     fn chat_constructor_sets_protocol_version_and_body() {
         let envelope = WireEnvelope::chat("alice", &RoomId::new("general"), "hi");
 
-        assert_eq!(envelope.version(), PROTOCOL_VERSION);
+        // every peer has chat, so it goes out under the oldest version
+        assert_eq!(envelope.version(), OLDEST_PROTOCOL_VERSION);
         assert_eq!(envelope.room, Some(RoomId::new("general")));
-        assert!(matches!(envelope.content, WireContent::Chat { ref body } if body == "hi"));
+        assert!(matches!(envelope.content, WireContent::Chat { ref body, .. } if body == "hi"));
+    }
+
+    #[test]
+    fn markdown_chat_says_so_and_arrives_formatted() {
+        let room = RoomId::new("general");
+
+        let plain = serde_json::to_value(WireEnvelope::chat("alice", &room, "hi")).unwrap();
+        assert_eq!(plain.get("format"), None);
+
+        let envelope = WireEnvelope::chat("alice", &room, "**hi**");
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(json["format"], "markdown");
+
+        match envelope.into_chat_event() {
+            ChatEvent::Message(ChatMessage {
+                body, formatted, ..
+            }) => {
+                assert_eq!(body, "**hi**");
+                assert_eq!(
+                    formatted.map(|rich| rich.blocks),
+                    Some(vec![Block::Paragraph(vec![Inline::Bold(vec![
+                        Inline::Text("hi".to_string())
+                    ])])])
+                );
+            }
+            other => panic!("expected ChatEvent::Message, got {:?}", other),
+        }
     }
 
     #[test]
@@ -276,6 +1114,328 @@ mod tests {
         assert_eq!(room.to_string(), "general");
     }
 
+    #[test]
+    fn chat_event_from_wire_is_plain_text_without_attachment() {
+        let envelope = WireEnvelope::chat("alice", &RoomId::new("general"), "hello");
+
+        match envelope.into_chat_event() {
+            ChatEvent::Message(ChatMessage {
+                kind, attachment, ..
+            }) => {
+                assert_eq!(kind, MessageKind::Text);
+                assert_eq!(attachment, None);
+            }
+            other => panic!("expected ChatEvent::Message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn human_size_picks_the_largest_fitting_unit() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+
+    #[test]
+    fn attachment_display_includes_known_details_only() {
+        let mut attachment = Attachment {
+            kind: AttachmentKind::Image,
+            name: "cat.png".to_string(),
+            size: Some(2048),
+            mime: Some("image/png".to_string()),
+        };
+        assert_eq!(
+            attachment.to_string(),
+            "image 'cat.png' (2.0 KiB, image/png)"
+        );
+
+        attachment.size = None;
+        attachment.mime = None;
+        assert_eq!(attachment.to_string(), "image 'cat.png'");
+    }
+
+    #[test]
+    fn room_invite_display_includes_name_and_inviter_when_known() {
+        let mut invite = RoomInvite {
+            room: RoomId::new("!abc:example.org"),
+            name: Some("Team".to_string()),
+            inviter: Some("@bob:example.org".to_string()),
+        };
+        assert_eq!(
+            invite.to_string(),
+            "Team (!abc:example.org) from @bob:example.org"
+        );
+
+        invite.name = None;
+        invite.inviter = None;
+        assert_eq!(invite.to_string(), "!abc:example.org");
+    }
+
+    #[test]
+    fn room_summary_display_hides_zero_unread() {
+        let mut summary = RoomSummary {
+            room: RoomId::new("!abc:example.org"),
+            name: "Team".to_string(),
+            alias: Some("#team:example.org".to_string()),
+            members: 3,
+            unread: 0,
+            highlights: 0,
+            notify: NotifyLevel::All,
+            direct: false,
+        };
+        assert_eq!(
+            summary.to_string(),
+            "Team (#team:example.org, !abc:example.org) - 3 members"
+        );
+
+        summary.alias = None;
+        summary.unread = 2;
+        assert_eq!(
+            summary.to_string(),
+            "Team (!abc:example.org) - 3 members, 2 unread"
+        );
+
+        summary.name = "Bob".to_string();
+        summary.direct = true;
+        assert_eq!(
+            summary.to_string(),
+            "Bob (direct message, !abc:example.org) - 3 members, 2 unread"
+        );
+
+        summary.highlights = 1;
+        summary.notify = NotifyLevel::Mentions;
+        assert_eq!(
+            summary.to_string(),
+            "Bob (direct message, !abc:example.org) - 3 members, 2 unread, 1 mentioning you [mentions only]"
+        );
+    }
+
+    #[test]
+    fn reaction_refers_to_the_target_envelope() {
+        let room = RoomId::new("general");
+        let target = MessageId::new("8f14e45f-ceea-467f-a0e2-2b1e6c6f1a53");
+
+        match WireEnvelope::reaction("bob", &room, &target, "👍", false).into_chat_event() {
+            ChatEvent::Reaction {
+                id,
+                user,
+                key,
+                added,
+                ..
+            } => {
+                assert_eq!(id, target);
+                assert_eq!(user, "bob");
+                assert_eq!(key, "👍");
+                assert!(added);
+            }
+            other => panic!("expected ChatEvent::Reaction, got {:?}", other),
+        }
+
+        // a reaction without the flag is one being added
+        let json = r#"{"v":2,"id":"1b4e28ba-2fa1-11d2-883f-0016d3cca427","ts":"2024-01-01T12:00:00Z","from":"bob","room":"general","type":"reaction","target":"$x","key":"🎉"}"#;
+        let envelope: WireEnvelope = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            envelope.into_chat_event(),
+            ChatEvent::Reaction { added: true, .. }
+        ));
+
+        match WireEnvelope::reaction("bob", &room, &target, "👍", true).into_chat_event() {
+            ChatEvent::Reaction { added, .. } => assert!(!added),
+            other => panic!("expected ChatEvent::Reaction, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn receipt_names_the_reader_and_the_message() {
+        let read = MessageId::new("8f14e45f-ceea-467f-a0e2-2b1e6c6f1a53");
+        let envelope = WireEnvelope::receipt("bob", &RoomId::new("general"), &read);
+
+        match envelope.clone().into_chat_event() {
+            ChatEvent::Receipt { room, user, id } => {
+                assert_eq!(room, RoomId::new("general"));
+                assert_eq!(user, "bob");
+                assert_eq!(id, read);
+            }
+            other => panic!("expected ChatEvent::Receipt, got {:?}", other),
+        }
+
+        let mut envelope = envelope;
+        envelope.room = None;
+        match envelope.into_chat_event() {
+            ChatEvent::System(text) => assert_eq!(text, "missing <room> for Receipt"),
+            other => panic!("expected ChatEvent::System, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn presence_travels_without_a_room() {
+        let away = Presence::away(Some("lunch".to_string()));
+        let json = serde_json::to_string(&WireEnvelope::presence("bob", &away)).unwrap();
+        assert!(json.contains(r#""type":"presence","state":"away","message":"lunch""#));
+
+        let decoded: WireEnvelope = serde_json::from_str(&json).unwrap();
+        match decoded.into_chat_event() {
+            ChatEvent::Presence { user, presence } => {
+                assert_eq!(user, "bob");
+                assert_eq!(presence, away);
+                assert_eq!(presence.to_string(), "away: lunch");
+            }
+            other => panic!("expected ChatEvent::Presence, got {:?}", other),
+        }
+
+        assert_eq!(Presence::default().to_string(), "online");
+    }
+
+    #[test]
+    fn types_from_newer_peers_parse_as_unknown() {
+        let json = r#"{"v":2,"id":"1b4e28ba-2fa1-11d2-883f-0016d3cca427","ts":"2024-01-01T12:00:00Z","from":"bob","room":"general","type":"poll","question":"lunch?"}"#;
+        let envelope: WireEnvelope = serde_json::from_str(json).unwrap();
+
+        assert!(matches!(envelope.content, WireContent::Unknown));
+    }
+
+    #[test]
+    fn version_2_peers_still_read_what_they_have() {
+        // WireContent as version 2 had it
+        #[allow(dead_code)]
+        #[derive(Deserialize)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum OldContent {
+            Chat { body: String },
+            Join,
+            Leave,
+            System { text: String },
+        }
+        #[derive(Deserialize)]
+        struct OldEnvelope {
+            v: u8,
+            #[serde(flatten)]
+            content: OldContent,
+        }
+
+        let room = RoomId::new("general");
+        for envelope in [
+            WireEnvelope::chat("bob", &room, "**hi**"),
+            WireEnvelope::join("bob", &room),
+            WireEnvelope::leave("bob", &room),
+        ] {
+            let json = serde_json::to_string(&envelope).unwrap();
+            let old: OldEnvelope = serde_json::from_str(&json).unwrap();
+            assert_eq!(old.v, OLDEST_PROTOCOL_VERSION);
+            assert!(!matches!(old.content, OldContent::System { .. }));
+        }
+
+        let id = MessageId::new("$1");
+        for envelope in [
+            WireEnvelope::typing("bob", &room, true),
+            WireEnvelope::reaction("bob", &room, &id, "👍", false),
+            WireEnvelope::receipt("bob", &room, &id),
+            WireEnvelope::presence("bob", &Presence::default()),
+        ] {
+            assert_eq!(envelope.version(), 3);
+        }
+
+        // their Join doesn't say what they speak, and ours does
+        let json = r#"{"v":2,"id":"1b4e28ba-2fa1-11d2-883f-0016d3cca427","ts":"2024-01-01T12:00:00Z","from":"bob","room":"general","type":"join"}"#;
+        let envelope: WireEnvelope = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            envelope.content,
+            WireContent::Join { speaks: None }
+        ));
+        assert!(matches!(
+            WireEnvelope::join("bob", &room).content,
+            WireContent::Join {
+                speaks: Some(PROTOCOL_VERSION)
+            }
+        ));
+    }
+
+    #[test]
+    fn mentions_match_whole_names_only() {
+        assert!(mentions("hey Alice, look", "alice"));
+        assert!(mentions("@alice: ping", "Alice"));
+        assert!(mentions("thanks alice", "alice"));
+        assert!(!mentions("malice aforethought", "alice"));
+        assert!(!mentions("alice_bot says hi", "alice"));
+        assert!(!mentions("anything", " "));
+
+        assert_eq!(NotifyLevel::All.attention(false), Attention::Notify);
+        assert_eq!(NotifyLevel::Mentions.attention(false), Attention::None);
+        assert_eq!(NotifyLevel::Mentions.attention(true), Attention::Highlight);
+        assert_eq!(NotifyLevel::Muted.attention(true), Attention::None);
+    }
+
+    #[test]
+    fn directory_room_address_prefers_alias_over_id() {
+        let mut room = DirectoryRoom {
+            room: RoomId::new("!abc:example.org"),
+            name: None,
+            alias: Some("#rust:example.org".to_string()),
+            topic: None,
+            members: 42,
+        };
+        assert_eq!(room.address(), RoomId::new("#rust:example.org"));
+
+        room.alias = None;
+        assert_eq!(room.address(), RoomId::new("!abc:example.org"));
+    }
+
+    #[test]
+    fn user_label_prefers_display_name_and_disambiguates_duplicates() {
+        assert_eq!(
+            user_label(Some("Alice"), "@alice:example.org", false),
+            "Alice"
+        );
+        assert_eq!(
+            user_label(Some("Alice"), "@alice:example.org", true),
+            "Alice (@alice:example.org)"
+        );
+        assert_eq!(
+            user_label(None, "@alice:example.org", false),
+            "@alice:example.org"
+        );
+        assert_eq!(
+            user_label(Some(" "), "@alice:example.org", false),
+            "@alice:example.org"
+        );
+    }
+
+    #[test]
+    fn connection_status_display_mentions_retry_delay() {
+        let status = ConnectionStatus::Reconnecting {
+            error: "connection refused".to_string(),
+            attempt: 3,
+            retry_in: Duration::from_secs(4),
+        };
+
+        assert_eq!(
+            status.to_string(),
+            "connection problem (connection refused), retrying in 4s (attempt 3)"
+        );
+    }
+
+    #[test]
+    fn power_levels_rank_creators_above_any_number() {
+        assert!(PowerLevel::Creator > PowerLevel::Level(i64::MAX));
+        assert!(PowerLevel::Level(100) > PowerLevel::Level(50));
+
+        let levels = PowerLevels {
+            own: PowerLevel::Level(50),
+            users: vec![("@admin:example.org".to_string(), PowerLevel::Creator)],
+            users_default: 0,
+            events_default: 0,
+            state_default: 50,
+            invite: 0,
+            kick: 50,
+            ban: 50,
+            redact: 50,
+        };
+
+        let shown = levels.to_string();
+        assert!(shown.starts_with("you: 50\n@admin:example.org: creator\neveryone else: 0\n"));
+        assert!(shown.contains("kick: 50"));
+    }
+
     #[test]
     fn wire_envelope_round_trips_through_json() {
         let original = WireEnvelope::chat("alice", &RoomId::new("general"), "hello");
@@ -285,6 +1445,6 @@ mod tests {
 
         assert_eq!(decoded.id, original.id);
         assert_eq!(decoded.room, original.room);
-        assert!(matches!(decoded.content, WireContent::Chat { ref body } if body == "hello"));
+        assert!(matches!(decoded.content, WireContent::Chat { ref body, .. } if body == "hello"));
     }
 }
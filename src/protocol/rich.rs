@@ -0,0 +1,615 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Spans open at once, bold inside a link inside italics and so on. Past this an
+/// opening marker is just text, which keeps the parse, and everything that walks
+/// the result, from recursing as deep as a hostile message likes.
+pub const MAX_NESTING: usize = 16;
+
+/// Longer bodies aren't parsed for formatting at all and show as plain text
+pub const MAX_FORMATTED_LEN: usize = 16 * 1024;
+
+/// A message body with its formatting, whether it came from the Markdown someone
+/// typed or the HTML a Matrix client sent. The plain `body` always travels alongside
+/// it, for anything that can't show styles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RichText {
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Block {
+    Paragraph(Vec<Inline>),
+    /// preformatted lines, e.g. a fenced code block, with the language it's in if given
+    Code {
+        language: Option<String>,
+        text: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inline {
+    Text(String),
+    Bold(Vec<Inline>),
+    Italic(Vec<Inline>),
+    Code(String),
+    Link { url: String, text: Vec<Inline> },
+    LineBreak,
+}
+
+impl RichText {
+    /// parses the Markdown we let people write: `**bold**`, `*italics*`, `` `code` ``,
+    /// fenced code blocks and `[links](https://…)`. Returns `None` if none of it is
+    /// used, so a plain message goes out as just that, however it's spaced.
+    pub fn from_markdown(source: &str) -> Option<Self> {
+        if source.len() > MAX_FORMATTED_LEN {
+            return None;
+        }
+
+        let text = Self {
+            blocks: markdown_blocks(source),
+        };
+        let words = |s: &str| s.split_whitespace().collect::<Vec<_>>().join(" ");
+        (words(&text.plain()) != words(source)).then_some(text)
+    }
+
+    /// the HTML for a Matrix `formatted_body`, using only tags the spec suggests
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+
+        for block in &self.blocks {
+            match block {
+                // a lone paragraph needs no <p>, which keeps short messages short
+                Block::Paragraph(inlines) if self.blocks.len() == 1 => {
+                    inline_html(&mut html, inlines);
+                }
+                Block::Paragraph(inlines) => {
+                    html.push_str("<p>");
+                    inline_html(&mut html, inlines);
+                    html.push_str("</p>");
+                }
+                Block::Code { language, text } => {
+                    html.push_str("<pre><code");
+                    if let Some(language) = language {
+                        let _ = write!(html, " class=\"language-{}\"", escape_html(language));
+                    }
+                    let _ = write!(html, ">{}</code></pre>", escape_html(text));
+                }
+            }
+        }
+
+        html
+    }
+
+    /// the text without any of its styling
+    fn plain(&self) -> String {
+        let blocks: Vec<String> = self
+            .blocks
+            .iter()
+            .map(|block| match block {
+                Block::Paragraph(inlines) => {
+                    let mut text = String::new();
+                    inline_plain(&mut text, inlines);
+                    text
+                }
+                Block::Code { text, .. } => text.clone(),
+            })
+            .collect();
+        blocks.join("\n\n")
+    }
+}
+
+fn inline_plain(out: &mut String, inlines: &[Inline]) {
+    for inline in inlines {
+        match inline {
+            Inline::Text(text) | Inline::Code(text) => out.push_str(text),
+            Inline::Bold(inner) | Inline::Italic(inner) | Inline::Link { text: inner, .. } => {
+                inline_plain(out, inner)
+            }
+            Inline::LineBreak => out.push('\n'),
+        }
+    }
+}
+
+fn inline_html(out: &mut String, inlines: &[Inline]) {
+    for inline in inlines {
+        match inline {
+            Inline::Text(text) => out.push_str(&escape_html(text)),
+            Inline::Bold(inner) => {
+                out.push_str("<strong>");
+                inline_html(out, inner);
+                out.push_str("</strong>");
+            }
+            Inline::Italic(inner) => {
+                out.push_str("<em>");
+                inline_html(out, inner);
+                out.push_str("</em>");
+            }
+            Inline::Code(text) => {
+                let _ = write!(out, "<code>{}</code>", escape_html(text));
+            }
+            Inline::Link { url, text } => {
+                let _ = write!(out, "<a href=\"{}\">", escape_html(url));
+                inline_html(out, text);
+                out.push_str("</a>");
+            }
+            Inline::LineBreak => out.push_str("<br>"),
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Splits Markdown into code fences and paragraphs. Unlike Markdown proper, a single
+/// newline is a line break: in a chat message it's always meant as one.
+fn markdown_blocks(source: &str) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut lines = source.lines();
+
+    while let Some(line) = lines.next() {
+        if let Some(info) = line.trim_start().strip_prefix("```") {
+            end_paragraph(&mut blocks, &mut paragraph);
+
+            // an unclosed fence runs to the end of the message
+            let code: Vec<&str> = lines
+                .by_ref()
+                .take_while(|line| line.trim() != "```")
+                .collect();
+            let language = info.trim();
+            blocks.push(Block::Code {
+                language: (!language.is_empty()).then(|| language.to_string()),
+                text: code.join("\n"),
+            });
+        } else if line.trim().is_empty() {
+            end_paragraph(&mut blocks, &mut paragraph);
+        } else {
+            paragraph.push(line);
+        }
+    }
+
+    end_paragraph(&mut blocks, &mut paragraph);
+    blocks
+}
+
+fn end_paragraph(blocks: &mut Vec<Block>, lines: &mut Vec<&str>) {
+    if lines.is_empty() {
+        return;
+    }
+
+    let chars: Vec<char> = lines.join("\n").chars().collect();
+    blocks.push(Block::Paragraph(parse_inline(&chars)));
+    lines.clear();
+}
+
+/// What opened a span that hasn't been closed yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Opener {
+    Emphasis { marker: char, width: usize },
+    Bracket,
+}
+
+/// A span being parsed: the root of the paragraph, or one whose closing marker
+/// hasn't turned up yet
+#[derive(Debug, Default)]
+struct Frame {
+    opener: Option<Opener>,
+    inlines: Vec<Inline>,
+    text: String,
+}
+
+impl Frame {
+    fn opened(opener: Opener) -> Self {
+        Self {
+            opener: Some(opener),
+            ..Self::default()
+        }
+    }
+
+    fn push(&mut self, inline: Inline) {
+        match inline {
+            Inline::Text(text) => self.text.push_str(&text),
+            inline => {
+                if !self.text.is_empty() {
+                    self.inlines
+                        .push(Inline::Text(std::mem::take(&mut self.text)));
+                }
+                self.inlines.push(inline);
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inlines.is_empty() && self.text.is_empty()
+    }
+
+    fn finish(mut self) -> Vec<Inline> {
+        if !self.text.is_empty() {
+            self.inlines.push(Inline::Text(self.text));
+        }
+        self.inlines
+    }
+}
+
+/// Parses a paragraph in one pass, keeping the spans still open on a stack rather
+/// than recursing into them. Scans ahead for a closing backtick run or `)` are
+/// remembered when they fail, so none is repeated and the whole parse stays linear.
+fn parse_inline(chars: &[char]) -> Vec<Inline> {
+    let mut stack = vec![Frame::default()];
+    // backtick run lengths with no closing run from the given position on
+    let mut no_code_close: HashMap<usize, usize> = HashMap::new();
+    // the position from which there's no `)` left
+    let mut no_paren_from: Option<usize> = None;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let top = stack.len() - 1;
+
+        match chars[i] {
+            '\\' if chars.get(i + 1).is_some_and(|c| c.is_ascii_punctuation()) => {
+                stack[top].text.push(chars[i + 1]);
+                i += 2;
+            }
+            '\n' => {
+                stack[top].push(Inline::LineBreak);
+                i += 1;
+            }
+            '`' => {
+                let run = backtick_run(chars, i);
+                match code_span(chars, i, run, &mut no_code_close) {
+                    Some((code, end)) => {
+                        stack[top].push(code);
+                        i = end;
+                    }
+                    // an unmatched run of backticks is literal, all of it
+                    None => {
+                        stack[top].text.extend(&chars[i..i + run]);
+                        i += run;
+                    }
+                }
+            }
+            '*' | '_' => i = emphasis(chars, i, &mut stack),
+            '[' if stack.len() < MAX_NESTING => {
+                stack.push(Frame::opened(Opener::Bracket));
+                i += 1;
+            }
+            ']' => i = close_link(chars, i, &mut stack, &mut no_paren_from),
+            c => {
+                stack[top].text.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    // whatever is still open was never closed, so its marker is just text
+    collapse_to(&mut stack, 0);
+    stack.pop().map(Frame::finish).unwrap_or_default()
+}
+
+/// Folds every span above `stack[depth]` back into its parent as plain text,
+/// markers included
+fn collapse_to(stack: &mut Vec<Frame>, depth: usize) {
+    while stack.len() > depth + 1 {
+        let frame = stack.pop().expect("stack is deeper than `depth`");
+        let parent = stack.last_mut().expect("the root is never popped here");
+
+        match frame.opener {
+            Some(Opener::Emphasis { marker, width }) => {
+                parent.text.extend(std::iter::repeat_n(marker, width))
+            }
+            Some(Opener::Bracket) => parent.text.push('['),
+            None => {}
+        }
+        for inline in frame.finish() {
+            parent.push(inline);
+        }
+    }
+}
+
+fn backtick_run(chars: &[char], start: usize) -> usize {
+    chars[start..].iter().take_while(|&&c| c == '`').count()
+}
+
+/// `` `code` ``, closed by a run of as many backticks as opened it
+fn code_span(
+    chars: &[char],
+    start: usize,
+    run: usize,
+    no_close: &mut HashMap<usize, usize>,
+) -> Option<(Inline, usize)> {
+    if no_close.get(&run).is_some_and(|&from| start >= from) {
+        return None;
+    }
+
+    let mut i = start + run;
+    while i < chars.len() {
+        if chars[i] != '`' {
+            i += 1;
+            continue;
+        }
+
+        let closing = backtick_run(chars, i);
+        if closing == run {
+            let code: String = chars[start + run..i]
+                .iter()
+                .map(|&c| if c == '\n' { ' ' } else { c })
+                .collect();
+            // one space either side lets code start or end with a backtick
+            let code = match code.strip_prefix(' ').and_then(|c| c.strip_suffix(' ')) {
+                Some(inner) if !inner.trim().is_empty() => inner.to_string(),
+                _ => code,
+            };
+            return Some((Inline::Code(code), i + run));
+        }
+        i += closing;
+    }
+
+    no_close.insert(run, start);
+    None
+}
+
+/// A run of `*` or `_` at `start`: it closes `*italics*`, `_italics_`, `**bold**` or
+/// `__bold__` opened earlier, opens new ones, or is just text. The markers have to
+/// hug the text they style, and `_` has to be at a word boundary, so "2 * 3 * 4" and
+/// snake_case names come through untouched. Returns where the run ends.
+fn emphasis(chars: &[char], start: usize, stack: &mut Vec<Frame>) -> usize {
+    let marker = chars[start];
+    let run = chars[start..].iter().take_while(|&&c| c == marker).count();
+    let end = start + run;
+
+    let before = start.checked_sub(1).map(|i| chars[i]);
+    let after = chars.get(end).copied();
+    let in_word = |c: Option<char>| marker == '_' && c.is_some_and(char::is_alphanumeric);
+    let can_open = after.is_some_and(|c| !c.is_whitespace()) && !in_word(before);
+    let can_close = before.is_some_and(|c| !c.is_whitespace()) && !in_word(after);
+
+    let mut left = run;
+
+    // a longer run, as in `**bold *italic***`, can close more than one span
+    while can_close && left > 0 {
+        // the innermost span this marker could close; emphasis doesn't cross brackets
+        let Some(depth) = stack.iter().rposition(|frame| {
+            frame.opener == Some(Opener::Bracket)
+                || matches!(frame.opener, Some(Opener::Emphasis { marker: m, .. }) if m == marker)
+        }) else {
+            break;
+        };
+        let Some(Opener::Emphasis { width, .. }) = stack[depth].opener else {
+            break;
+        };
+        if width > left || (depth == stack.len() - 1 && stack[depth].is_empty()) {
+            break;
+        }
+
+        collapse_to(stack, depth);
+        let inner = stack.pop().expect("the span being closed").finish();
+        let styled = if width == 2 {
+            Inline::Bold(inner)
+        } else {
+            Inline::Italic(inner)
+        };
+        stack.last_mut().expect("the root").push(styled);
+        left -= width;
+    }
+
+    // three or more open bold first, then italics inside it
+    while can_open && left > 0 && stack.len() < MAX_NESTING {
+        let width = left.min(2);
+        stack.push(Frame::opened(Opener::Emphasis { marker, width }));
+        left -= width;
+    }
+
+    let top = stack.last_mut().expect("the root");
+    top.text.extend(std::iter::repeat_n(marker, left));
+    end
+}
+
+/// A `]` at `at`: closes `[text](url)` if a `[` is open and a URL follows, otherwise
+/// it and the `[` it would have closed are text. Returns where parsing carries on.
+fn close_link(
+    chars: &[char],
+    at: usize,
+    stack: &mut Vec<Frame>,
+    no_paren_from: &mut Option<usize>,
+) -> usize {
+    let Some(depth) = stack
+        .iter()
+        .rposition(|frame| frame.opener == Some(Opener::Bracket))
+    else {
+        stack.last_mut().expect("the root").text.push(']');
+        return at + 1;
+    };
+
+    let target = link_target(chars, at + 1, no_paren_from);
+    collapse_to(stack, depth);
+
+    match target {
+        Some((url, end)) if !stack[depth].is_empty() => {
+            let text = stack.pop().expect("the bracket").finish();
+            stack
+                .last_mut()
+                .expect("the root")
+                .push(Inline::Link { url, text });
+            end
+        }
+        _ => {
+            collapse_to(stack, depth - 1);
+            stack.last_mut().expect("the root").text.push(']');
+            at + 1
+        }
+    }
+}
+
+/// `(url)` at `at`, and where it ends
+fn link_target(
+    chars: &[char],
+    at: usize,
+    no_paren_from: &mut Option<usize>,
+) -> Option<(String, usize)> {
+    if chars.get(at) != Some(&'(') || no_paren_from.is_some_and(|from| at >= from) {
+        return None;
+    }
+
+    let Some(close) = chars[at..].iter().position(|&c| c == ')').map(|n| at + n) else {
+        *no_paren_from = Some(at);
+        return None;
+    };
+
+    let url: String = chars[at + 1..close].iter().collect();
+    let url = url.trim();
+    if url.is_empty() || url.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((url.to_string(), close + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Inline {
+        Inline::Text(s.to_string())
+    }
+
+    fn paragraph(markdown: &str) -> Vec<Inline> {
+        match RichText::from_markdown(markdown)
+            .map(|rich| rich.blocks)
+            .as_deref()
+        {
+            Some([Block::Paragraph(inlines)]) => inlines.clone(),
+            other => panic!("expected one paragraph, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn plain_messages_stay_plain() {
+        assert_eq!(RichText::from_markdown("hello there"), None);
+        assert_eq!(RichText::from_markdown("2 * 3 * 4 = 24"), None);
+        assert_eq!(RichText::from_markdown("call snake_case_name()"), None);
+        assert_eq!(RichText::from_markdown("first line\nsecond"), None);
+        assert_eq!(RichText::from_markdown("[not a link] (really)"), None);
+        assert_eq!(RichText::from_markdown("  spaced out \n\n\n\nparas "), None);
+    }
+
+    #[test]
+    fn inline_styles_nest_and_code_is_taken_literally() {
+        assert_eq!(
+            paragraph("**bold *and italic*** then `a * b`"),
+            [
+                Inline::Bold(vec![
+                    text("bold "),
+                    Inline::Italic(vec![text("and italic")])
+                ]),
+                text(" then "),
+                Inline::Code("a * b".to_string()),
+            ]
+        );
+        assert_eq!(
+            paragraph("_so_ \\*not\\* italic"),
+            [Inline::Italic(vec![text("so")]), text(" *not* italic")]
+        );
+        assert_eq!(
+            paragraph("see [the **docs**](https://example.org/a_b)"),
+            [
+                text("see "),
+                Inline::Link {
+                    url: "https://example.org/a_b".to_string(),
+                    text: vec![text("the "), Inline::Bold(vec![text("docs")])],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn fenced_code_keeps_its_lines_and_language() {
+        let rich =
+            RichText::from_markdown("look:\n```rust\nlet a = *b;\n\nok()\n```\nneat").unwrap();
+
+        assert_eq!(
+            rich.blocks,
+            [
+                Block::Paragraph(vec![text("look:")]),
+                Block::Code {
+                    language: Some("rust".to_string()),
+                    text: "let a = *b;\n\nok()".to_string(),
+                },
+                Block::Paragraph(vec![text("neat")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn html_escapes_text_and_wraps_paragraphs_only_when_there_are_several() {
+        let one = RichText::from_markdown("**<b>** & [x](https://a.org/?q=\"1\")").unwrap();
+        assert_eq!(
+            one.to_html(),
+            "<strong>&lt;b&gt;</strong> &amp; <a href=\"https://a.org/?q=&quot;1&quot;\">x</a>"
+        );
+
+        let several = RichText::from_markdown("*hi*\nthere\n\n```sh\nls <dir>\n```").unwrap();
+        assert_eq!(
+            several.to_html(),
+            "<p><em>hi</em><br>there</p><pre><code class=\"language-sh\">ls &lt;dir&gt;</code></pre>"
+        );
+    }
+
+    fn depth(inlines: &[Inline]) -> usize {
+        inlines
+            .iter()
+            .map(|inline| match inline {
+                Inline::Bold(inner) | Inline::Italic(inner) | Inline::Link { text: inner, .. } => {
+                    1 + depth(inner)
+                }
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn deep_nesting_is_capped_and_long_bodies_stay_plain() {
+        let links = format!("{}a{}", "[".repeat(8000), "](u)".repeat(8000));
+        assert_eq!(RichText::from_markdown(&links), None);
+
+        let chars: Vec<char> = links.chars().collect();
+        let parsed = parse_inline(&chars);
+        assert!(depth(&parsed) < MAX_NESTING);
+        let mut plain = String::new();
+        inline_plain(&mut plain, &parsed);
+        assert_eq!(plain.matches('a').count(), 1);
+
+        let stars = format!("{}{}", "*a ".repeat(4000), "a* ".repeat(4000));
+        let chars: Vec<char> = stars.chars().collect();
+        assert!(depth(&parse_inline(&chars)) < MAX_NESTING);
+
+        let nested = format!("{}**x**{}", "[".repeat(40), "](u)".repeat(40));
+        let rich = RichText::from_markdown(&nested).unwrap();
+        let Block::Paragraph(inlines) = &rich.blocks[0] else {
+            panic!("not a paragraph: {:?}", rich.blocks);
+        };
+        assert!(depth(inlines) < MAX_NESTING);
+    }
+
+    #[test]
+    fn thousands_of_unclosed_openers_stay_one_run_of_text() {
+        let unclosed = "**a ".repeat(4000);
+        let chars: Vec<char> = unclosed.chars().collect();
+
+        // each opener left open goes back to being text, not a level of nesting
+        let parsed = parse_inline(&chars);
+        assert_eq!(parsed, [Inline::Text(unclosed)]);
+    }
+}